@@ -2,6 +2,8 @@ pub const MIL: usize = 1_000_000;
 pub const TMIL: usize = 10_000_000;
 pub const HMIL: usize = 100_000_000;
 pub const TKILO: usize = 10_000;
+pub const ALN_BATCH_SIZE: usize = 100_000;
+pub const SORT_BUFFER_SIZE: usize = 5_000_000;
 
 pub const FRAG_DIST: i64 = 10;
 pub const MATE_MIN_DISTANCE: i64 = 20;
@@ -15,3 +17,7 @@ pub const NUM_SUPPORT_CB: u32 = 5;
 pub const MIN_FEAT_COUNT: u64 = 5;
 pub const WINDOW_SIZE: i64 = 500;
 pub const PILEUP_THRESHOLD: u16 = 15;
+pub const GENE_UPSTREAM: u32 = 2_000;
+pub const PROMOTER_UPSTREAM: u32 = 2_000;
+pub const PROMOTER_DOWNSTREAM: u32 = 100;
+pub const SPIKEIN_SCALE: f32 = 10_000.0;
@@ -0,0 +1,141 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::schema::{cb_with_library, Fragment, FragmentFile};
+
+// merge order of the fragments, the input files have to be sorted by the
+// chromosome and start
+fn fragment_key(frag: &Fragment) -> (u32, u64, u64, u64) {
+    (frag.chr, frag.start, frag.end, frag.cb)
+}
+
+// median number of fragments of the barcodes w/ at least min_frags
+fn median_cell_depth(path: &Path, min_frags: usize) -> Result<usize, Box<dyn Error>> {
+    let mut cb_frags: HashMap<u64, usize> = HashMap::new();
+    for frag in FragmentFile::from_path(path)? {
+        *cb_frags.entry(frag.cb).or_insert(0) += 1;
+    }
+
+    let mut depths: Vec<usize> = cb_frags
+        .values()
+        .filter(|x| **x >= min_frags)
+        .copied()
+        .collect();
+    if depths.is_empty() {
+        return Err(format!("no cells w/ >= {} fragments in {:?}", min_frags, path).into());
+    }
+
+    depths.sort_unstable();
+    Ok(depths[depths.len() / 2])
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut input_paths: Vec<PathBuf> = Vec::new();
+    for path in sub_m.values_of("ibeds").expect("can't find input BED flag") {
+        let file_path = Path::new(path)
+            .canonicalize()
+            .expect("can't find absolute path of input BED file");
+        info!("Found BED file: {:?}", file_path);
+        input_paths.push(file_path);
+    }
+
+    // the libraries are numbered from 1 in the input order, like the -N
+    // barcode suffix of Cell Ranger aggr, the barcodes of the first library
+    // are written w/o the suffix
+    let mut keep_fractions = vec![1.0; input_paths.len()];
+    if sub_m.is_present("normalize") {
+        let min_frags: usize = sub_m
+            .value_of("minfrags")
+            .unwrap_or("1000")
+            .parse()
+            .expect("can't parse min fragments");
+
+        let mut depths = Vec::with_capacity(input_paths.len());
+        for path in &input_paths {
+            depths.push(median_cell_depth(path, min_frags)?);
+        }
+        let min_depth = *depths.iter().min().unwrap();
+
+        for (library, depth) in depths.iter().enumerate() {
+            keep_fractions[library] = min_depth as f64 / *depth as f64;
+            info!(
+                "Library {} has median {} fragments per cell, keeping {:.02}%",
+                library + 1,
+                depth.to_formatted_string(&Locale::en),
+                keep_fractions[library] * 100.0
+            );
+        }
+    }
+    let seed: u64 = sub_m
+        .value_of("seed")
+        .unwrap_or("0")
+        .parse()
+        .expect("can't parse seed");
+
+    let mut inputs = Vec::with_capacity(input_paths.len());
+    for path in &input_paths {
+        inputs.push(FragmentFile::from_path(path)?);
+    }
+
+    // k-way merge w/ the head fragment of every library in the heap
+    let mut heads: Vec<Option<Fragment>> = Vec::with_capacity(inputs.len());
+    let mut heap = BinaryHeap::new();
+    for (library, input) in inputs.iter_mut().enumerate() {
+        let head = input.next();
+        if let Some(frag) = &head {
+            heap.push(Reverse((fragment_key(frag), library)));
+        }
+        heads.push(head);
+    }
+
+    let mut obed_file = carina::file::bufwriter_from_clap(sub_m, "obed")?;
+    let mut num_frags = 0;
+    let mut library_frags = vec![0; inputs.len()];
+    while let Some(Reverse((key, library))) = heap.pop() {
+        let mut frag = heads[library].take().unwrap();
+
+        heads[library] = inputs[library].next();
+        if let Some(next_frag) = &heads[library] {
+            assert!(
+                (next_frag.chr, next_frag.start) >= (key.0, key.1),
+                "input BED file {:?} is not sorted",
+                input_paths[library]
+            );
+            heap.push(Reverse((fragment_key(next_frag), library)));
+        }
+
+        num_frags += 1;
+        if num_frags % crate::configs::MIL == 0 {
+            print!(
+                "\rDone processing {}M fragments",
+                num_frags / crate::configs::MIL
+            );
+            std::io::stdout().flush().expect("Can't flush output");
+        }
+
+        if keep_fractions[library] < 1.0 && frag.hash_fraction(seed) >= keep_fractions[library] {
+            continue;
+        }
+
+        frag.cb = cb_with_library(frag.cb, library as u64);
+        frag.write(&mut obed_file, "binary")?;
+        library_frags[library] += 1;
+    }
+    println!();
+
+    for (library, num_library_frags) in library_frags.iter().enumerate() {
+        info!(
+            "Wrote {} fragments from library {}: {:?}",
+            num_library_frags.to_formatted_string(&Locale::en),
+            library + 1,
+            input_paths[library]
+        );
+    }
+    Ok(())
+}
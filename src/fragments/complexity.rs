@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use clap::ArgMatches;
+use itertools::Itertools;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::schema::{cb_to_string, FragmentFile};
+
+// sequencing depth relative to the current one at which the curves are
+// reported, > 1 is extrapolated
+const DEPTH_FRACTIONS: [f64; 16] = [
+    0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0, 20.0,
+];
+// max number of terms of the Good-Toulmin series in the continued fraction
+const MAX_SERIES_TERMS: usize = 100;
+const MIN_SERIES_TERMS: usize = 4;
+
+// Lander-Waterman estimate of the number of unique molecules in the library,
+// as in Picard, solves c / x - 1 + exp(-n / x) = 0 for x w/ n reads and c
+// unique reads. None if there is no duplicate to estimate from.
+pub fn lander_waterman(num_reads: f64, num_unique: f64) -> Option<f64> {
+    if num_unique <= 0.0 || num_unique >= num_reads {
+        return None;
+    }
+
+    let f = |x: f64| num_unique / x - 1.0 + (-num_reads / x).exp();
+    let mut lower = num_unique;
+    let mut upper = num_unique * 2.0;
+    while f(upper) > 0.0 {
+        lower = upper;
+        upper *= 2.0;
+    }
+
+    for _ in 0..100 {
+        let mid = (lower + upper) / 2.0;
+        match f(mid) > 0.0 {
+            true => lower = mid,
+            false => upper = mid,
+        };
+    }
+
+    Some((lower + upper) / 2.0)
+}
+
+fn lander_waterman_unique(complexity: f64, num_reads: f64) -> f64 {
+    complexity * (1.0 - (-num_reads / complexity).exp())
+}
+
+// Rational function (continued fraction) approximation of a power series
+// from its coefficients through the quotient-difference algorithm, the
+// same idea Preseq uses to extrapolate the Good-Toulmin series.
+struct ContinuedFraction {
+    offset: f64,
+    coeffs: Vec<f64>,
+}
+
+impl ContinuedFraction {
+    fn from_series(series: &[f64]) -> Option<ContinuedFraction> {
+        let num_terms = series.len();
+        if num_terms < 2 || series.iter().any(|x| *x == 0.0) {
+            return None;
+        }
+
+        // q[k] and e[k] hold the current column of the QD table
+        let mut coeffs = Vec::with_capacity(num_terms - 1);
+        let mut q: Vec<f64> = (0..num_terms - 1)
+            .map(|k| series[k + 1] / series[k])
+            .collect();
+        let mut e: Vec<f64> = vec![0.0; num_terms];
+        coeffs.push(q[0]);
+
+        for _ in 1..num_terms - 1 {
+            if coeffs.len() == num_terms - 1 || q.len() < 2 {
+                break;
+            }
+
+            let new_e: Vec<f64> = (0..q.len() - 1)
+                .map(|k| q[k + 1] - q[k] + e[k + 1])
+                .collect();
+            coeffs.push(new_e[0]);
+            if coeffs.len() == num_terms - 1 || new_e.len() < 2 {
+                break;
+            }
+
+            let new_q: Vec<f64> = (0..new_e.len() - 1)
+                .map(|k| q[k + 1] * new_e[k + 1] / new_e[k])
+                .collect();
+            coeffs.push(new_q[0]);
+
+            q = new_q;
+            e = new_e;
+        }
+
+        match coeffs.iter().all(|x| x.is_finite()) {
+            true => Some(ContinuedFraction {
+                offset: series[0],
+                coeffs,
+            }),
+            false => None,
+        }
+    }
+
+    // c0 / (1 - a1 x / (1 - a2 x / (1 - ...)))
+    fn evaluate(&self, x: f64) -> f64 {
+        let mut denom = 1.0;
+        for coeff in self.coeffs.iter().rev() {
+            denom = 1.0 - coeff * x / denom;
+        }
+
+        self.offset / denom
+    }
+}
+
+// frequency of the fragment counts, histogram[j] is the number of unique
+// fragments seen exactly j times
+pub struct Complexity {
+    histogram: Vec<u64>,
+    num_reads: u64,
+    num_unique: u64,
+}
+
+impl Complexity {
+    pub fn new(histogram: Vec<u64>) -> Complexity {
+        let num_reads = histogram
+            .iter()
+            .enumerate()
+            .map(|(count, num)| count as u64 * num)
+            .sum();
+        let num_unique = histogram.iter().sum();
+
+        Complexity {
+            histogram,
+            num_reads,
+            num_unique,
+        }
+    }
+
+    pub fn duplication_rate(&self) -> f64 {
+        1.0 - self.num_unique as f64 / self.num_reads as f64
+    }
+
+    // expected unique fragments w/ a random fraction of the reads
+    fn interpolate(&self, fraction: f64) -> f64 {
+        self.histogram
+            .iter()
+            .enumerate()
+            .map(|(count, num)| *num as f64 * (1.0 - (1.0 - fraction).powi(count as i32)))
+            .sum()
+    }
+
+    // Good-Toulmin series sum_i (-1)^(i+1) h_i x^i w/ x = t - 1, the
+    // continued fraction is taken w/ fewer terms until the curve is
+    // increasing and concave on the reported depths
+    fn extrapolation(&self) -> Option<ContinuedFraction> {
+        let max_terms = self
+            .histogram
+            .iter()
+            .skip(1)
+            .take_while(|x| **x > 0)
+            .count()
+            .min(MAX_SERIES_TERMS);
+
+        let mut num_terms = max_terms - max_terms % 2;
+        while num_terms >= MIN_SERIES_TERMS {
+            let series: Vec<f64> = (1..=num_terms)
+                .map(|i| match i % 2 {
+                    1 => self.histogram[i] as f64,
+                    _ => -(self.histogram[i] as f64),
+                })
+                .collect();
+
+            if let Some(cf) = ContinuedFraction::from_series(&series) {
+                if self.is_well_behaved(&cf) {
+                    return Some(cf);
+                }
+            }
+            num_terms -= 2;
+        }
+
+        None
+    }
+
+    fn is_well_behaved(&self, cf: &ContinuedFraction) -> bool {
+        let mut prev_unique = self.num_unique as f64;
+        let mut prev_slope = f64::INFINITY;
+        let mut prev_fraction = 1.0;
+        for fraction in DEPTH_FRACTIONS.iter().filter(|x| **x > 1.0) {
+            let x = fraction - 1.0;
+            let unique = self.num_unique as f64 + x * cf.evaluate(x);
+            let slope = (unique - prev_unique) / (fraction - prev_fraction);
+            if !unique.is_finite() || slope < 0.0 || slope > prev_slope {
+                return false;
+            }
+
+            prev_unique = unique;
+            prev_slope = slope;
+            prev_fraction = *fraction;
+        }
+
+        true
+    }
+
+    // number of terms of the continued fraction, None if unstable
+    pub fn extrapolation_terms(&self) -> Option<usize> {
+        self.extrapolation().map(|cf| cf.coeffs.len() + 1)
+    }
+
+    // expected unique fragments at each of the DEPTH_FRACTIONS, the
+    // Lander-Waterman curve is used if the extrapolation is unstable
+    pub fn curve(&self) -> Vec<f64> {
+        let extrapolation = self.extrapolation();
+        let complexity = lander_waterman(self.num_reads as f64, self.num_unique as f64);
+
+        DEPTH_FRACTIONS
+            .iter()
+            .map(
+                |fraction| match (*fraction <= 1.0, &extrapolation, complexity) {
+                    (true, _, _) => self.interpolate(*fraction),
+                    (false, Some(cf), _) => {
+                        let x = fraction - 1.0;
+                        self.num_unique as f64 + x * cf.evaluate(x)
+                    }
+                    (false, None, Some(complexity)) => {
+                        lander_waterman_unique(complexity, fraction * self.num_reads as f64)
+                    }
+                    // no duplicates, every new read is a new fragment
+                    (false, None, None) => fraction * self.num_reads as f64,
+                },
+            )
+            .collect()
+    }
+}
+
+fn add_to_histogram(histogram: &mut Vec<u64>, count: u64) {
+    if histogram.len() <= count as usize {
+        histogram.resize(count as usize + 1, 0);
+    }
+    histogram[count as usize] += 1;
+}
+
+// the non-finite values, e.g. a cell w/o fragments, are left out
+fn median(mut values: Vec<f64>) -> f64 {
+    values.retain(|x| x.is_finite());
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    values[values.len() / 2]
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let input_bed = BufReader::new(File::open(ibed_file_path)?);
+    let min_frags: u64 = sub_m
+        .value_of("minfrags")
+        .unwrap_or("1000")
+        .parse()
+        .expect("can't parse min fragments");
+
+    // the input has to be grouped by chromosome, as from the filter
+    let mut histogram: Vec<u64> = vec![0; 2];
+    let mut cb_histograms: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut frag_counts: HashMap<(u64, u64, u64), u64> = HashMap::new();
+    for (chr, chr_group) in FragmentFile::new(input_bed)
+        .group_by(|frag| frag.chr)
+        .into_iter()
+    {
+        print!("\rWorking on Chromosome: {}", chr);
+        std::io::stdout().flush().expect("Can't flush output");
+
+        for frag in chr_group {
+            *frag_counts
+                .entry((frag.start, frag.end, frag.cb))
+                .or_insert(0) += 1;
+        }
+
+        for ((_, _, cb), count) in frag_counts.drain() {
+            add_to_histogram(&mut histogram, count);
+            add_to_histogram(cb_histograms.entry(cb).or_insert_with(|| vec![0; 2]), count);
+        }
+    }
+    println!();
+
+    let library = Complexity::new(histogram);
+    info!(
+        "Saw total {} fragments w/ {} unique, {:.02}% duplication rate",
+        library.num_reads.to_formatted_string(&Locale::en),
+        library.num_unique.to_formatted_string(&Locale::en),
+        library.duplication_rate() * 100.0
+    );
+    if let Some(complexity) = lander_waterman(library.num_reads as f64, library.num_unique as f64) {
+        info!(
+            "Estimated library complexity: {} unique fragments",
+            (complexity as u64).to_formatted_string(&Locale::en)
+        );
+    }
+    match library.extrapolation_terms() {
+        Some(num_terms) => info!(
+            "Extrapolating w/ a continued fraction of {} terms",
+            num_terms
+        ),
+        None => info!("Unstable extrapolation, using the Lander-Waterman estimate"),
+    };
+
+    let out_prefix = sub_m.value_of("output").expect("can't find output flag");
+    let cells_path = format!("{}.cells.tsv", out_prefix);
+    info!("Creating per cell complexity file: {:?}", cells_path);
+    let mut cells_file = BufWriter::new(File::create(Path::new(&cells_path))?);
+    writeln!(
+        cells_file,
+        "barcode\tnum_fragments\tnum_unique\tduplication_rate\testimated_complexity"
+    )?;
+
+    // per cell curves w/ the same extrapolation as the library one
+    let cell_curves_path = format!("{}.cells.complexity.tsv", out_prefix);
+    info!(
+        "Creating per cell complexity curves file: {:?}",
+        cell_curves_path
+    );
+    let mut cell_curves_file = BufWriter::new(File::create(Path::new(&cell_curves_path))?);
+    writeln!(
+        cell_curves_file,
+        "barcode\tdepth_fraction\tnum_fragments\texpected_unique\tsaturation"
+    )?;
+
+    let mut cb_histograms: Vec<(u64, Vec<u64>)> = cb_histograms.into_iter().collect();
+    cb_histograms.sort_unstable_by_key(|(cb, _)| *cb);
+
+    let mut cell_curves: Vec<(u64, Vec<f64>)> = Vec::new();
+    let mut num_extrapolated = 0;
+    for (cb, cb_histogram) in cb_histograms {
+        let cell = Complexity::new(cb_histogram);
+        if cell.num_unique < min_frags {
+            continue;
+        }
+
+        let cb_str = cb_to_string(cb)?;
+        let complexity = lander_waterman(cell.num_reads as f64, cell.num_unique as f64);
+        writeln!(
+            cells_file,
+            "{}\t{}\t{}\t{:.04}\t{}",
+            cb_str,
+            cell.num_reads,
+            cell.num_unique,
+            cell.duplication_rate(),
+            complexity.map_or("NA".to_string(), |x| format!("{:.0}", x))
+        )?;
+
+        if cell.extrapolation_terms().is_some() {
+            num_extrapolated += 1;
+        }
+        let curve = cell.curve();
+        for (fraction, expected_unique) in DEPTH_FRACTIONS.iter().zip(curve.iter()) {
+            let num_reads = fraction * cell.num_reads as f64;
+            writeln!(
+                cell_curves_file,
+                "{}\t{}\t{:.0}\t{:.0}\t{:.04}",
+                cb_str,
+                fraction,
+                num_reads,
+                expected_unique,
+                1.0 - expected_unique / num_reads
+            )?;
+        }
+        cell_curves.push((cell.num_reads, curve));
+    }
+    info!(
+        "Found {} cells w/ >= {} unique fragments, {} w/ a stable extrapolation",
+        cell_curves.len().to_formatted_string(&Locale::en),
+        min_frags,
+        num_extrapolated.to_formatted_string(&Locale::en)
+    );
+
+    let curve_path = format!("{}.complexity.tsv", out_prefix);
+    info!("Creating complexity curve file: {:?}", curve_path);
+    let mut curve_file = BufWriter::new(File::create(Path::new(&curve_path))?);
+    writeln!(
+        curve_file,
+        "depth_fraction\tnum_fragments\texpected_unique\tsaturation\tmedian_cell_fragments\tmedian_cell_unique"
+    )?;
+    for (idx, (fraction, expected_unique)) in
+        DEPTH_FRACTIONS.iter().zip(library.curve()).enumerate()
+    {
+        let num_reads = fraction * library.num_reads as f64;
+        let cell_reads: Vec<f64> = cell_curves
+            .iter()
+            .map(|(num_reads, _)| fraction * *num_reads as f64)
+            .collect();
+        let cell_unique: Vec<f64> = cell_curves.iter().map(|(_, curve)| curve[idx]).collect();
+
+        writeln!(
+            curve_file,
+            "{}\t{:.0}\t{:.0}\t{:.04}\t{:.0}\t{:.0}",
+            fraction,
+            num_reads,
+            expected_unique,
+            1.0 - expected_unique / num_reads,
+            median(cell_reads),
+            median(cell_unique)
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continued_fraction_of_exp() {
+        let series: Vec<f64> = [1.0, 1.0, 2.0, 6.0, 24.0, 120.0, 720.0]
+            .iter()
+            .map(|x| 1.0 / x)
+            .collect();
+        let cf = ContinuedFraction::from_series(&series).unwrap();
+        assert_eq!(cf.coeffs.len(), series.len() - 1);
+        assert!((cf.coeffs[1] + 0.5).abs() < 1e-12);
+        assert!((cf.evaluate(0.5) - 0.5f64.exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn continued_fraction_needs_nonzero_terms() {
+        assert!(ContinuedFraction::from_series(&[1.0]).is_none());
+        assert!(ContinuedFraction::from_series(&[1.0, 0.0, 1.0]).is_none());
+    }
+
+    #[test]
+    fn curve_interpolates_the_observed_depth() {
+        let complexity = Complexity::new(vec![0, 10, 5]);
+        let curve = complexity.curve();
+        assert_eq!(curve.len(), DEPTH_FRACTIONS.len());
+        assert!((curve[4] - 8.75).abs() < 1e-9);
+        assert!((curve[9] - 15.0).abs() < 1e-9);
+        assert!(curve.windows(2).all(|x| x[0] < x[1]));
+    }
+
+    #[test]
+    fn curve_wo_duplicates_is_linear() {
+        let complexity = Complexity::new(vec![0, 10]);
+        assert_eq!(complexity.extrapolation_terms(), None);
+        let curve = complexity.curve();
+        assert!((curve[11] - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn median_skips_non_finite_values() {
+        assert_eq!(median(vec![3.0, f64::NAN, 1.0, 2.0]), 2.0);
+        assert_eq!(median(vec![f64::NAN]), 0.0);
+    }
+}
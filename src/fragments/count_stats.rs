@@ -1,38 +1,70 @@
 use num_format::{Locale, ToFormattedString};
 
+use crate::fragments::spikein::SpikeInCounts;
+
 #[derive(Default)]
 pub struct FragStats {
     pub total_reads: usize,
     pub mm_reads: usize,
+    pub mm_kept: usize,
     pub mapq_skip: usize,
     pub chimeric_tids: usize,
     pub chimeric_strand: usize,
     pub chimeric_max_distance: usize,
     pub chimeric_min_distance: usize,
-    pub mito_skip: usize,
+    pub contig_skip: usize,
     pub unmap_skip: usize,
     pub unmap_orphan: usize,
+    pub half_frags: usize,
     pub cb_skip: usize,
+    // rejected read groups per filter name, seeded w/ the filter chain so
+    // the order doesn't depend on which filter rejected a read group first
+    pub filter_skips: Vec<(&'static str, usize)>,
+    pub spikein: SpikeInCounts,
 }
 
 impl FragStats {
-    fn num_skipped(&self) -> usize {
-        self.mm_reads
-            + self.mapq_skip
-            + self.chimeric_tids
-            + self.chimeric_strand
-            + self.chimeric_max_distance
-            + self.chimeric_min_distance
-            + self.mito_skip
-            + self.unmap_skip
-            + self.cb_skip
+    pub fn with_filters(names: &[&'static str]) -> FragStats {
+        FragStats {
+            filter_skips: names.iter().map(|name| (*name, 0)).collect(),
+            ..Default::default()
+        }
     }
 
-    fn num_chimeric(&self) -> usize {
-        self.chimeric_tids
-            + self.chimeric_strand
-            + self.chimeric_max_distance
-            + self.chimeric_min_distance
+    pub fn merge(&mut self, other: &FragStats) {
+        self.total_reads += other.total_reads;
+        self.mm_reads += other.mm_reads;
+        self.mm_kept += other.mm_kept;
+        self.mapq_skip += other.mapq_skip;
+        self.chimeric_tids += other.chimeric_tids;
+        self.chimeric_strand += other.chimeric_strand;
+        self.chimeric_max_distance += other.chimeric_max_distance;
+        self.chimeric_min_distance += other.chimeric_min_distance;
+        self.contig_skip += other.contig_skip;
+        self.unmap_skip += other.unmap_skip;
+        self.unmap_orphan += other.unmap_orphan;
+        self.half_frags += other.half_frags;
+        self.cb_skip += other.cb_skip;
+        self.spikein.merge(&other.spikein);
+        for (name, count) in &other.filter_skips {
+            self.add_filter_skips(name, *count);
+        }
+    }
+
+    pub fn add_filter_skip(&mut self, name: &'static str) {
+        self.add_filter_skips(name, 1);
+    }
+
+    fn add_filter_skips(&mut self, name: &'static str, count: usize) {
+        match self.filter_skips.iter_mut().find(|(x, _)| *x == name) {
+            Some((_, total)) => *total += count,
+            None => self.filter_skips.push((name, count)),
+        };
+    }
+
+    // every rejected read group is counted against exactly one filter
+    fn num_skipped(&self) -> usize {
+        self.filter_skips.iter().map(|(_, count)| count).sum()
     }
 
     fn percent_total(&self, num: usize) -> f32 {
@@ -44,7 +76,6 @@ impl FragStats {
 impl std::fmt::Display for FragStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let total_skipped = self.num_skipped();
-        let total_chimeric = self.num_chimeric();
 
         let mut stats: String = String::new();
         stats += &format!(
@@ -52,37 +83,23 @@ impl std::fmt::Display for FragStats {
             (self.total_reads).to_formatted_string(&Locale::en)
         );
         stats += &format!(
-            "STATS: Total Unmapped skip: {}({:.02}%) with {}({:.02}%) HighQ orphan.\n",
-            (self.unmap_skip).to_formatted_string(&Locale::en),
-            self.percent_total(self.unmap_skip),
-            (self.unmap_orphan).to_formatted_string(&Locale::en),
-            self.percent_total(self.unmap_orphan)
-        );
-        stats += &format!(
-            "STATS: Total MultiMapping Reads: {}({:.02}%).\n",
-            (self.mm_reads).to_formatted_string(&Locale::en),
-            self.percent_total(self.mm_reads)
-        );
-        stats += &format!(
-            "STATS: Total Mitochondrial Reads: {}({:.02}%).\n",
-            (self.mito_skip).to_formatted_string(&Locale::en),
-            self.percent_total(self.mito_skip)
-        );
-        stats += &format!(
-            "STATS: Total MAPQ skip: {}({:.02}%).\n",
-            (self.mapq_skip).to_formatted_string(&Locale::en),
-            self.percent_total(self.mapq_skip)
-        );
-        stats += &format!(
-            "STATS: Total Chimeric Reads: {}({:.02}%)\n",
-            (total_chimeric).to_formatted_string(&Locale::en),
-            self.percent_total(total_chimeric)
+            "STATS: Total Orphan/Single-end Reads kept as Insertions: {}({:.02}%).\n",
+            (self.half_frags).to_formatted_string(&Locale::en),
+            self.percent_total(self.half_frags)
         );
         stats += &format!(
-            "STATS: Total Reads w/ Unknown CB: {}({:.02}%)\n",
-            (self.cb_skip).to_formatted_string(&Locale::en),
-            self.percent_total(self.cb_skip)
+            "STATS: Total MultiMapping Reads kept: {}({:.02}%).\n",
+            (self.mm_kept).to_formatted_string(&Locale::en),
+            self.percent_total(self.mm_kept)
         );
+        for (name, count) in &self.filter_skips {
+            stats += &format!(
+                "STATS: Filter {} skip: {}({:.02}%)\n",
+                name,
+                count.to_formatted_string(&Locale::en),
+                self.percent_total(*count)
+            );
+        }
         stats += &format!(
             "STATS: Total Reads skipped: {}({:.02}%)\n",
             (total_skipped).to_formatted_string(&Locale::en),
@@ -0,0 +1,389 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+use rust_htslib::bam;
+use rust_htslib::bam::Read;
+
+use crate::fragments::schema::{Fragment, FragmentFile};
+use crate::fragments::spikein::{scale_factor, SpikeInCounts};
+
+// chromosome names and lengths indexed by the chr id of the fragments
+pub struct ChromSizes {
+    pub names: Vec<String>,
+    pub lengths: Vec<u64>,
+}
+
+impl ChromSizes {
+    pub fn from_bam(path: &str) -> Result<ChromSizes, Box<dyn Error>> {
+        let bam_file_path = Path::new(path)
+            .canonicalize()
+            .expect("can't find absolute path of input BAM file");
+        info!("Found BAM file: {:?}", bam_file_path);
+
+        let input_bam = bam::Reader::from_path(bam_file_path).expect("Can't open BAM file");
+        let header = input_bam.header();
+
+        let mut chrom_sizes = ChromSizes {
+            names: Vec::new(),
+            lengths: Vec::new(),
+        };
+        for tid in 0..header.target_count() {
+            chrom_sizes
+                .names
+                .push(std::str::from_utf8(header.tid2name(tid))?.to_string());
+            chrom_sizes.lengths.push(header.target_len(tid).unwrap());
+        }
+
+        Ok(chrom_sizes)
+    }
+
+    // two column name, length file in the order of the BAM header
+    pub fn from_file(path: &str) -> Result<ChromSizes, Box<dyn Error>> {
+        let file_path = Path::new(path)
+            .canonicalize()
+            .expect("can't find absolute path of input chrom sizes file");
+        info!("Found chrom sizes file: {:?}", file_path);
+
+        let mut chrom_sizes = ChromSizes {
+            names: Vec::new(),
+            lengths: Vec::new(),
+        };
+        for (line_num, line) in BufReader::new(File::open(file_path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.trim_end().split('\t').collect();
+            if toks.len() < 2 {
+                return Err(format!(
+                    "chrom sizes line {} needs a name and a length: {}",
+                    line_num + 1,
+                    line
+                )
+                .into());
+            }
+            let length = toks[1].parse().map_err(|_| {
+                format!(
+                    "can't parse the length on chrom sizes line {}: {}",
+                    line_num + 1,
+                    line
+                )
+            })?;
+            chrom_sizes.names.push(toks[0].to_string());
+            chrom_sizes.lengths.push(length);
+        }
+
+        Ok(chrom_sizes)
+    }
+
+    pub fn from_clap(sub_m: &ArgMatches) -> Result<ChromSizes, Box<dyn Error>> {
+        match (sub_m.value_of("bam"), sub_m.value_of("chromsizes")) {
+            (Some(path), _) => ChromSizes::from_bam(path),
+            (None, Some(path)) => ChromSizes::from_file(path),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    pub fn write(&self, out_path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(out_path)?);
+        for (name, length) in self.names.iter().zip(self.lengths.iter()) {
+            writeln!(file, "{}\t{}", name, length)?;
+        }
+
+        Ok(())
+    }
+
+    // chr ids in the byte order of the names, as bedGraphToBigWig wants
+    fn sorted_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = (0..self.names.len()).collect();
+        ids.sort_by(|a, b| self.names[*a].cmp(&self.names[*b]));
+        ids
+    }
+}
+
+// Binned genome wide coverage, either the per base fragment pileup
+// averaged over the bin or the number of Tn5 cut sites in the bin.
+pub struct Coverage {
+    bin_size: u64,
+    is_insertion: bool,
+    bins: Vec<Vec<f32>>,
+    total: u64,
+}
+
+impl Coverage {
+    pub fn new(chrom_sizes: &ChromSizes, bin_size: u64, mode: &str) -> Coverage {
+        assert!(bin_size > 0, "bin size has to be positive");
+        let bins = chrom_sizes
+            .lengths
+            .iter()
+            .map(|length| Vec::with_capacity((length / bin_size + 1) as usize))
+            .collect();
+
+        Coverage {
+            bin_size,
+            is_insertion: mode == "insertion",
+            bins,
+            total: 0,
+        }
+    }
+
+    fn add_to_bin(&mut self, chr: usize, bin: usize, val: f32) {
+        let chr_bins = &mut self.bins[chr];
+        if chr_bins.len() <= bin {
+            chr_bins.resize(bin + 1, 0.0);
+        }
+        chr_bins[bin] += val;
+    }
+
+    // the chr id has to be in the chrom sizes, e.g. not from another reference
+    pub fn add(&mut self, frag: &Fragment) -> Result<(), Box<dyn Error>> {
+        let chr = frag.chr as usize;
+        if chr >= self.bins.len() {
+            return Err(format!(
+                "fragment chr id {} is not in the {} chrom sizes",
+                frag.chr,
+                self.bins.len()
+            )
+            .into());
+        }
+
+        match self.is_insertion {
+            true => {
+                let mut cut_sites = vec![frag.start];
+                if !frag.is_insertion() {
+                    cut_sites.push(frag.end - 1);
+                }
+
+                for cut_site in cut_sites {
+                    self.add_to_bin(chr, (cut_site / self.bin_size) as usize, 1.0);
+                    self.total += 1;
+                }
+            }
+            false => {
+                let mut pos = frag.start;
+                while pos < frag.end {
+                    let bin = pos / self.bin_size;
+                    let bin_end = std::cmp::min(frag.end, (bin + 1) * self.bin_size);
+                    let overlap = (bin_end - pos) as f32 / self.bin_size as f32;
+                    self.add_to_bin(chr, bin as usize, overlap);
+                    pos = bin_end;
+                }
+                self.total += 1;
+            }
+        };
+
+        Ok(())
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // scaling factor of the raw values, the spike-in reads are the sum over
+    // the barcodes of the track. The fragment bins already hold the mean
+    // coverage per base, so only the cut-site counts have a per kb rpkm.
+    pub fn scale(&self, norm: &str, spikein_reads: Option<usize>) -> Result<f32, Box<dyn Error>> {
+        match norm {
+            "none" => Ok(1.0),
+            "cpm" => Ok(1e6 / self.total as f32),
+            "rpkm" => match self.is_insertion {
+                true => Ok(1e9 / (self.total as f32 * self.bin_size as f32)),
+                false => Err("rpkm normalization needs the insertion mode, \
+                    the fragment coverage already is per base, use cpm"
+                    .into()),
+            },
+            "spikein" => {
+                let spikein_reads =
+                    spikein_reads.ok_or("spike-in normalization needs the --spikein counts")?;
+                Ok(scale_factor(spikein_reads)
+                    .ok_or("no spike-in reads found to normalize with")?)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // consecutive bins w/ the same value are merged, zero bins are skipped
+    pub fn write_bedgraph(
+        &self,
+        file: &mut BufWriter<File>,
+        chrom_sizes: &ChromSizes,
+        scale: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        for chr in chrom_sizes.sorted_ids() {
+            let chr_name = &chrom_sizes.names[chr];
+            let chr_len = chrom_sizes.lengths[chr];
+
+            let mut run: Option<(u64, u64, f32)> = None;
+            for (bin, val) in self.bins[chr].iter().enumerate() {
+                let start = bin as u64 * self.bin_size;
+                let end = std::cmp::min(chr_len, start + self.bin_size);
+                if start >= end {
+                    break;
+                }
+
+                let val = val * scale;
+                run = match run {
+                    Some((run_start, _, run_val)) if run_val == val => Some((run_start, end, val)),
+                    _ => {
+                        if let Some((run_start, run_end, run_val)) = run {
+                            if run_val != 0.0 {
+                                writeln!(
+                                    file,
+                                    "{}\t{}\t{}\t{}",
+                                    chr_name, run_start, run_end, run_val
+                                )?;
+                            }
+                        }
+                        Some((start, end, val))
+                    }
+                };
+            }
+
+            if let Some((run_start, run_end, run_val)) = run {
+                if run_val != 0.0 {
+                    writeln!(
+                        file,
+                        "{}\t{}\t{}\t{}",
+                        chr_name, run_start, run_end, run_val
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_track(
+        &self,
+        chrom_sizes: &ChromSizes,
+        scale: f32,
+        out_path: &Path,
+        format: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        match format {
+            "bedgraph" => {
+                info!("Creating output bedGraph file: {:?}", out_path);
+                let mut file = BufWriter::new(File::create(out_path)?);
+                self.write_bedgraph(&mut file, chrom_sizes, scale)
+            }
+            "bigwig" => write_bigwig(self, chrom_sizes, scale, out_path),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn write_bigwig(
+    coverage: &Coverage,
+    chrom_sizes: &ChromSizes,
+    scale: f32,
+    out_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let out_str = out_path.to_str().unwrap();
+    let bedgraph_path = PathBuf::from(format!("{}.bedGraph.tmp", out_str));
+    let sizes_path = PathBuf::from(format!("{}.chrom.sizes.tmp", out_str));
+
+    let result = convert_to_bigwig(
+        coverage,
+        chrom_sizes,
+        scale,
+        out_path,
+        &bedgraph_path,
+        &sizes_path,
+    );
+
+    // the temporary files are removed even if the conversion failed
+    for tmp_path in &[bedgraph_path, sizes_path] {
+        if tmp_path.exists() {
+            std::fs::remove_file(tmp_path)?;
+        }
+    }
+    result
+}
+
+fn convert_to_bigwig(
+    coverage: &Coverage,
+    chrom_sizes: &ChromSizes,
+    scale: f32,
+    out_path: &Path,
+    bedgraph_path: &Path,
+    sizes_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    {
+        let mut file = BufWriter::new(File::create(bedgraph_path)?);
+        coverage.write_bedgraph(&mut file, chrom_sizes, scale)?;
+        file.flush()?;
+    } // closing the file.
+    chrom_sizes.write(sizes_path)?;
+
+    info!("Converting to bigWig w/ bedGraphToBigWig");
+    let status = Command::new("bedGraphToBigWig")
+        .arg(bedgraph_path)
+        .arg(sizes_path)
+        .arg(out_path)
+        .status()
+        .map_err(|err| format!("can't run bedGraphToBigWig, is it in the PATH? {}", err))?;
+    if !status.success() {
+        return Err(format!("bedGraphToBigWig failed w/ {}", status).into());
+    }
+
+    Ok(())
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let input_bed = BufReader::new(File::open(ibed_file_path)?);
+
+    let chrom_sizes = ChromSizes::from_clap(sub_m)?;
+    let bin_size: u64 = sub_m
+        .value_of("binsize")
+        .unwrap_or("50")
+        .parse()
+        .expect("can't parse bin size");
+    let mode = sub_m.value_of("mode").unwrap_or("fragment");
+    info!("Computing {} coverage w/ {}bp bins", mode, bin_size);
+
+    let mut num_frags = 0;
+    let mut coverage = Coverage::new(&chrom_sizes, bin_size, mode);
+    for frag in FragmentFile::new(input_bed) {
+        num_frags += 1;
+        if num_frags % crate::configs::MIL == 0 {
+            print!(
+                "\rDone processing {}M fragments",
+                num_frags / crate::configs::MIL
+            );
+            std::io::stdout().flush().expect("Can't flush output");
+        }
+
+        coverage.add(&frag)?;
+    }
+    println!();
+
+    let spikein = match sub_m.value_of("spikein") {
+        Some(path) => Some(SpikeInCounts::from_file(Path::new(path))?),
+        None => None,
+    };
+    let norm = sub_m.value_of("norm").unwrap_or("none");
+    let scale = coverage.scale(norm, spikein.map(|x| x.total()))?;
+    info!(
+        "Found {} {}, using {} normalization w/ scale {}",
+        coverage.total.to_formatted_string(&Locale::en),
+        match coverage.is_insertion {
+            true => "insertions",
+            false => "fragments",
+        },
+        norm,
+        scale
+    );
+
+    let out_path = Path::new(sub_m.value_of("output").expect("can't find output flag"));
+    let format = sub_m.value_of("format").unwrap_or("bedgraph");
+    coverage.write_track(&chrom_sizes, scale, out_path, format)?;
+
+    Ok(())
+}
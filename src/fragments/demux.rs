@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::groups::BarcodeGroups;
+use crate::fragments::schema::FragmentFile;
+
+const UNASSIGNED: &str = "unassigned";
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let input_bed = BufReader::new(File::open(ibed_file_path)?);
+
+    let samples =
+        BarcodeGroups::from_file(sub_m.value_of("samples").expect("can't find samples flag"))?;
+    let out_dir = PathBuf::from(
+        sub_m
+            .value_of("odir")
+            .expect("can't find output directory flag"),
+    );
+    std::fs::create_dir_all(&out_dir)?;
+
+    let write_mode = sub_m.value_of("format").unwrap_or("binary");
+    let extension = match write_mode {
+        "binary" => "bed",
+        _ => "tsv",
+    };
+
+    // the last file has the fragments of the unassigned barcodes
+    let num_samples = samples.names.len();
+    let mut file_names = samples.file_names(&[UNASSIGNED])?;
+    file_names.push(UNASSIGNED.to_string());
+
+    let mut sample_files = Vec::with_capacity(file_names.len());
+    for file_name in &file_names {
+        let file_path = out_dir.join(format!("{}.fragments.{}", file_name, extension));
+        info!("Creating output fragment file: {:?}", file_path);
+        sample_files.push(BufWriter::new(File::create(file_path)?));
+    }
+
+    let mut num_frags = 0;
+    let mut sample_frags = vec![0; file_names.len()];
+    let mut sample_cbs: Vec<HashSet<u64>> = vec![HashSet::new(); file_names.len()];
+    for frag in FragmentFile::new(input_bed) {
+        num_frags += 1;
+        if num_frags % crate::configs::MIL == 0 {
+            print!(
+                "\rDone processing {}M fragments",
+                num_frags / crate::configs::MIL
+            );
+            std::io::stdout().flush().expect("Can't flush output");
+        }
+
+        let sample_id = *samples.cb_groups.get(&frag.cb).unwrap_or(&num_samples);
+        frag.write(&mut sample_files[sample_id], write_mode)?;
+        sample_frags[sample_id] += 1;
+        sample_cbs[sample_id].insert(frag.cb);
+    }
+    println!();
+    for mut file in sample_files {
+        file.flush()?;
+    }
+
+    let summary_path = out_dir.join("demux_summary.tsv");
+    info!("Creating summary file: {:?}", summary_path);
+    let mut summary_file = BufWriter::new(File::create(summary_path)?);
+    writeln!(summary_file, "sample\tnum_barcodes\tnum_fragments")?;
+    for sample_id in 0..file_names.len() {
+        let sample_name = match sample_id == num_samples {
+            true => UNASSIGNED,
+            false => &samples.names[sample_id],
+        };
+        writeln!(
+            summary_file,
+            "{}\t{}\t{}",
+            sample_name,
+            sample_cbs[sample_id].len(),
+            sample_frags[sample_id]
+        )?;
+    }
+
+    info!(
+        "Assigned {} out of {} fragments to {} samples",
+        (num_frags - sample_frags[num_samples]).to_formatted_string(&Locale::en),
+        num_frags.to_formatted_string(&Locale::en),
+        num_samples
+    );
+    Ok(())
+}
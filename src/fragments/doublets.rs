@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use bio::data_structures::interval_tree::IntervalTree;
+use clap::ArgMatches;
+use itertools::Itertools;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::coverage::ChromSizes;
+use crate::fragments::filter::match_contig_names;
+use crate::fragments::schema::{cb_to_string, FragmentFile};
+use crate::preprocess::group::joint_classes;
+
+// min number of overlapping fragments of a cell to call a locus, a diploid
+// cell can have at most two
+const MIN_OVERLAPS: i32 = 3;
+
+// repeat/blacklist regions per chr id, loci overlapping these are skipped
+fn read_blacklist(
+    path: &str,
+    chrom_sizes: &ChromSizes,
+) -> Result<HashMap<u32, IntervalTree<u64, ()>>, Box<dyn Error>> {
+    let file_path = Path::new(path)
+        .canonicalize()
+        .expect("can't find absolute path of input blacklist BED file");
+    info!("Found blacklist BED file: {:?}", file_path);
+
+    let chr_ids: HashMap<&str, u32> = chrom_sizes
+        .names
+        .iter()
+        .enumerate()
+        .map(|(chr, name)| (name.as_str(), chr as u32))
+        .collect();
+
+    let mut blacklist: HashMap<u32, IntervalTree<u64, ()>> = HashMap::new();
+    for line in BufReader::new(File::open(file_path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        let toks: Vec<&str> = line.split('\t').collect();
+        if let Some(chr) = chr_ids.get(toks[0]) {
+            let range = Range {
+                start: toks[1].parse()?,
+                end: toks[2].parse()?,
+            };
+            blacklist
+                .entry(*chr)
+                .or_insert_with(IntervalTree::new)
+                .insert(range, ());
+        }
+    }
+
+    Ok(blacklist)
+}
+
+// merged regions covered by at least MIN_OVERLAPS fragments of a cell
+fn overlap_loci(frags: &[(u64, u64)]) -> Vec<Range<u64>> {
+    // the ends come before the starts at the same position
+    let mut events: Vec<(u64, i32)> = Vec::with_capacity(frags.len() * 2);
+    for (start, end) in frags {
+        events.push((*start, 1));
+        events.push((*end, -1));
+    }
+    events.sort();
+
+    let mut loci: Vec<Range<u64>> = Vec::new();
+    let mut depth = 0;
+    let mut locus_start = None;
+    for (pos, change) in events {
+        depth += change;
+        match (depth >= MIN_OVERLAPS, locus_start) {
+            (true, None) => locus_start = Some(pos),
+            (false, Some(start)) => {
+                locus_start = None;
+                match loci.last_mut() {
+                    Some(last) if last.end >= start => last.end = pos,
+                    _ => loci.push(start..pos),
+                };
+            }
+            _ => (),
+        };
+    }
+
+    loci
+}
+
+fn log_add(a: f64, b: f64) -> f64 {
+    let (hi, lo) = match a > b {
+        true => (a, b),
+        false => (b, a),
+    };
+    match hi == f64::NEG_INFINITY {
+        true => hi,
+        false => hi + (lo - hi).exp().ln_1p(),
+    }
+}
+
+// log of the sum of the Poisson pmf from i = k away from the mode, the
+// terms are only decreasing, so the sum stops once they are negligible
+fn log_poisson_sum(k: u64, lambda: f64, is_upper: bool) -> f64 {
+    let log_factorial: f64 = (1..=k).map(|x| (x as f64).ln()).sum();
+    let mut log_term = -lambda + k as f64 * lambda.ln() - log_factorial;
+    let mut log_sum = f64::NEG_INFINITY;
+    let mut i = k;
+    loop {
+        log_sum = log_add(log_sum, log_term);
+        match is_upper {
+            true => {
+                i += 1;
+                log_term += (lambda / i as f64).ln();
+            }
+            false => {
+                if i == 0 {
+                    break;
+                }
+                log_term += (i as f64 / lambda).ln();
+                i -= 1;
+            }
+        };
+        if log_term < log_sum + (1e-16f64).ln() {
+            break;
+        }
+    }
+
+    log_sum
+}
+
+// P(X >= k) for X ~ Poisson(lambda). The pmf is summed in log space, as
+// exp(-lambda) underflows for a lambda above ~745, either over the upper
+// tail or, below the mean, as one minus the lower tail.
+pub fn poisson_upper_tail(k: u64, lambda: f64) -> f64 {
+    if k == 0 {
+        return 1.0;
+    }
+    if lambda <= 0.0 {
+        return 0.0;
+    }
+
+    match k as f64 > lambda {
+        true => log_poisson_sum(k, lambda, true).exp().min(1.0),
+        false => (1.0 - log_poisson_sum(k - 1, lambda, false).exp()).max(0.0),
+    }
+}
+
+// Benjamini-Hochberg adjusted p-values
+pub fn adjust_pvalues(pvalues: &[f64]) -> Vec<f64> {
+    let num_tests = pvalues.len() as f64;
+    let mut order: Vec<usize> = (0..pvalues.len()).collect();
+    order.sort_by(|a, b| pvalues[*b].partial_cmp(&pvalues[*a]).unwrap());
+
+    let mut qvalues = vec![1.0; pvalues.len()];
+    let mut min_qvalue: f64 = 1.0;
+    for (rank, idx) in order.into_iter().enumerate() {
+        let num_smaller = num_tests - rank as f64;
+        min_qvalue = min_qvalue.min(pvalues[idx] * num_tests / num_smaller);
+        qvalues[idx] = min_qvalue;
+    }
+
+    qvalues
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let input_bed = BufReader::new(File::open(ibed_file_path)?);
+
+    let chrom_sizes = ChromSizes::from_clap(sub_m)?;
+    let blacklist = match sub_m.value_of("blacklist") {
+        Some(path) => read_blacklist(path, &chrom_sizes)?,
+        None => HashMap::new(),
+    };
+
+    // sex and mitochondrial chromosomes don't follow the two copy rule
+    let exclude: Vec<&str> = sub_m
+        .values_of("exclude")
+        .map_or(Vec::new(), |x| x.collect());
+    let names: Vec<&str> = chrom_sizes.names.iter().map(|x| x.as_str()).collect();
+    let excluded_chrs = match_contig_names(&names, &exclude)?;
+
+    let min_frags: usize = sub_m
+        .value_of("minfrags")
+        .unwrap_or("1000")
+        .parse()
+        .expect("can't parse min fragments");
+    let max_qvalue: f64 = sub_m
+        .value_of("qvalue")
+        .unwrap_or("0.01")
+        .parse()
+        .expect("can't parse q-value threshold");
+
+    // the input has to be grouped by chromosome, as for the dedup
+    let mut cb_frags: HashMap<u64, usize> = HashMap::new();
+    let mut cb_loci: HashMap<u64, u64> = HashMap::new();
+    for (chr, chr_group) in FragmentFile::new(input_bed)
+        .group_by(|frag| frag.chr)
+        .into_iter()
+    {
+        let is_excluded = match excluded_chrs.get(chr as usize) {
+            Some(is_excluded) => *is_excluded,
+            None => {
+                return Err(format!(
+                    "chromosome id {} is out of range of the {} chromosome sizes",
+                    chr,
+                    excluded_chrs.len()
+                )
+                .into())
+            }
+        };
+
+        let chr_classes = joint_classes(chr_group, |frag| {
+            *cb_frags.entry(frag.cb).or_insert(0) += 1;
+            match is_excluded {
+                true => None,
+                false => Some(frag.cb),
+            }
+        });
+        if is_excluded {
+            continue;
+        }
+
+        // unique fragments of every cell on the chromosome
+        let mut chr_cb_frags: HashMap<u64, Vec<(u64, u64)>> = HashMap::new();
+        for (range, cbs) in chr_classes {
+            for cb in cbs {
+                chr_cb_frags
+                    .entry(cb)
+                    .or_insert_with(Vec::new)
+                    .push((range.start, range.end));
+            }
+        }
+
+        print!(
+            "\rWorking on Chromosome: {}",
+            chrom_sizes.names[chr as usize]
+        );
+        std::io::stdout().flush().expect("Can't flush output");
+
+        let chr_blacklist = blacklist.get(&chr);
+        for (cb, mut frags) in chr_cb_frags {
+            frags.sort_unstable();
+
+            let num_loci = overlap_loci(&frags)
+                .into_iter()
+                .filter(|locus| match chr_blacklist {
+                    Some(tree) => tree.find(locus.clone()).next().is_none(),
+                    None => true,
+                })
+                .count();
+            *cb_loci.entry(cb).or_insert(0) += num_loci as u64;
+        }
+    }
+    println!();
+
+    let mut cells: Vec<u64> = cb_frags
+        .iter()
+        .filter(|(_, num_frags)| **num_frags >= min_frags)
+        .map(|(cb, _)| *cb)
+        .collect();
+    cells.sort_unstable();
+    if cells.is_empty() {
+        return Err(format!("no cells w/ >= {} fragments found", min_frags).into());
+    }
+
+    // expected number of overlap loci is the mean over the cells
+    let cell_loci: Vec<u64> = cells
+        .iter()
+        .map(|cb| *cb_loci.get(cb).unwrap_or(&0))
+        .collect();
+    let lambda = cell_loci.iter().sum::<u64>() as f64 / cells.len() as f64;
+    let pvalues: Vec<f64> = cell_loci
+        .iter()
+        .map(|num_loci| poisson_upper_tail(*num_loci, lambda))
+        .collect();
+    let qvalues = adjust_pvalues(&pvalues);
+
+    let out_path = Path::new(sub_m.value_of("output").expect("can't find output flag"));
+    info!("Creating doublets file: {:?}", out_path);
+    let mut file = BufWriter::new(File::create(out_path)?);
+    writeln!(
+        file,
+        "barcode\tnum_fragments\toverlap_loci\tp_value\tq_value\tis_doublet"
+    )?;
+
+    let mut num_doublets = 0;
+    for (idx, cb) in cells.iter().enumerate() {
+        let is_doublet = qvalues[idx] < max_qvalue;
+        if is_doublet {
+            num_doublets += 1;
+        }
+
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{:e}\t{:e}\t{}",
+            cb_to_string(*cb)?,
+            cb_frags[cb],
+            cell_loci[idx],
+            pvalues[idx],
+            qvalues[idx],
+            is_doublet
+        )?;
+    }
+
+    info!(
+        "Called {} doublets out of {} cells, w/ mean {:.02} overlap loci per cell",
+        num_doublets.to_formatted_string(&Locale::en),
+        cells.len().to_formatted_string(&Locale::en),
+        lambda
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poisson_upper_tail_on_both_sides_of_the_mean() {
+        assert_eq!(poisson_upper_tail(0, 3.0), 1.0);
+        assert_eq!(poisson_upper_tail(2, 0.0), 0.0);
+        assert!((poisson_upper_tail(1, 2.0) - (1.0 - (-2.0f64).exp())).abs() < 1e-12);
+        assert!((poisson_upper_tail(3, 1.0) - (1.0 - 2.5 * (-1.0f64).exp())).abs() < 1e-12);
+        assert!((poisson_upper_tail(5, 5.0) - 0.559_506_714_934_787_8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn poisson_upper_tail_w_a_large_mean() {
+        let at_mean = poisson_upper_tail(1_000, 1_000.0);
+        assert!(at_mean > 0.4 && at_mean < 0.6);
+        assert!(poisson_upper_tail(2_000, 1_000.0) < 1e-100);
+        assert!(poisson_upper_tail(500, 1_000.0) > 1.0 - 1e-12);
+    }
+
+    #[test]
+    fn adjust_pvalues_benjamini_hochberg() {
+        let qvalues = adjust_pvalues(&[0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        for (qvalue, expected) in qvalues.iter().zip(expected.iter()) {
+            assert!((qvalue - expected).abs() < 1e-12);
+        }
+        assert!(adjust_pvalues(&[]).is_empty());
+    }
+}
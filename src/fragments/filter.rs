@@ -1,131 +1,929 @@
-use rust_htslib::bam::record::Record;
+use std::hash::Hasher;
+
+use clap::ArgMatches;
+use regex::Regex;
+use rust_htslib::bam::record::{Aux, Record};
+use rust_htslib::bam::HeaderView;
 
 use crate::fragments::count_stats::FragStats;
-use crate::fragments::schema::soft_clip_pos;
+use crate::fragments::schema::{soft_clip_pos, StableHasher};
 
 use crate::configs::{MATE_MAX_DISTANCE, MATE_MIN_DISTANCE, MIN_MAPQ};
 
-fn is_unmapped(alignments: &[Record], counter: &mut FragStats) -> bool {
-    let mut no_map_count = 0;
-    for alignment in alignments {
-        if alignment.is_unmapped() {
-            no_map_count += 1;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Unmapped,
+    Orphan,
+    Multimap,
+    Mito,
+    Contig,
+    Mapq,
+    ChimericTid,
+    ChimericStrand,
+    TooFar,
+    TooClose,
+    NoCb,
+    SpikeIn,
+    // rejected by a filter outside this module, w/ the filter name
+    Custom(&'static str),
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::Unmapped => "unmapped",
+            RejectReason::Orphan => "orphan",
+            RejectReason::Multimap => "multimap",
+            RejectReason::Mito => "mito",
+            RejectReason::Contig => "contig",
+            RejectReason::Mapq => "mapq",
+            RejectReason::ChimericTid => "chimeric_tid",
+            RejectReason::ChimericStrand => "chimeric_strand",
+            RejectReason::TooFar => "too_far",
+            RejectReason::TooClose => "too_close",
+            RejectReason::NoCb => "no_cb",
+            RejectReason::SpikeIn => "spikein",
+            RejectReason::Custom(name) => name,
+        }
+    }
+}
+
+// records dropped from a read group before the filters run, w/ the reason
+pub type DroppedRecords = Vec<(RejectReason, Vec<Record>)>;
+
+// alignments of a read group which passed all the filters, the pair is
+// ordered w/ the forward strand read first
+pub enum FragmentAlignments<'a> {
+    Pair(&'a Record, &'a Record),
+    Insertion(&'a Record),
+}
+
+impl<'a> FragmentAlignments<'a> {
+    pub fn records(&self) -> Vec<&'a Record> {
+        match self {
+            FragmentAlignments::Pair(aln, maln) => vec![aln, maln],
+            FragmentAlignments::Insertion(aln) => vec![aln],
         }
     }
+}
+
+// A single check on the group of alignments of a read pair. The filter
+// returns the reason if the read group is rejected and can update its own
+// counters in the FragStats, the chain keeps the per filter reject counts.
+pub trait FragmentFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason>;
+}
 
-    if no_map_count > 0 {
-        counter.unmap_skip += 1;
-        if no_map_count != alignments.len() {
+// An orphan is a read pair w/ only one mapped mate. The orphans and the
+// reads of a single-end library are kept as half-fragments (a single Tn5
+// insertion) only if asked for, otherwise they are rejected.
+pub struct UnmappedFilter {
+    pub keep_orphans: bool,
+}
+
+impl FragmentFilter for UnmappedFilter {
+    fn name(&self) -> &'static str {
+        "unmapped"
+    }
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        let mut no_map_count = 0;
+        for alignment in alignments {
+            if alignment.is_unmapped() {
+                no_map_count += 1;
+            }
+        }
+
+        if no_map_count > 0 {
+            counter.unmap_skip += 1;
+            if no_map_count != alignments.len() {
+                counter.unmap_orphan += 1;
+                return Some(RejectReason::Orphan);
+            }
+            return Some(RejectReason::Unmapped);
+        }
+
+        // the unmapped mate is already dropped if the orphans are kept
+        if alignments.len() == 1 && !self.keep_orphans {
+            counter.unmap_skip += 1;
             counter.unmap_orphan += 1;
+            return Some(RejectReason::Orphan);
         }
-        return true;
+
+        None
     }
+}
 
-    false
+// How the read groups w/ more than one hit are handled. The aligners mark
+// a multimapper either w/ secondary records (minimap2, bowtie2 -k) or w/
+// tags on the primary records (XA/XS for bwa, XS for bowtie2, NH for most).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultimapPolicy {
+    // reject on any secondary record or a bwa XA tag, the other aligners'
+    // tags are only checked by the primary policy
+    Strict,
+    // drop the secondary/supplementary records, reject on the tags
+    Primary,
+    // drop the secondary/supplementary records, leave it to the MAPQ filter
+    Mapq,
+    // keep a random best scoring pair out of the primary and secondary pairs
+    Random,
+    // keep the primary pair and mark the records w/ ZM:i:1, the tag is kept
+    // in the rejects output
+    Flag,
 }
 
-fn is_multi_mapping(alignments: &[Record], counter: &mut FragStats) -> bool {
-    if alignments.len() > 2
-        || alignments.first().unwrap().aux(b"XA").is_some()
-        || alignments.last().unwrap().aux(b"XA").is_some()
-    {
-        counter.mm_reads += 1;
-        return true;
+impl Default for MultimapPolicy {
+    fn default() -> MultimapPolicy {
+        MultimapPolicy::Strict
     }
+}
 
-    false
+impl MultimapPolicy {
+    pub fn from_name(name: &str) -> MultimapPolicy {
+        match name {
+            "strict" => MultimapPolicy::Strict,
+            "primary" => MultimapPolicy::Primary,
+            "mapq" => MultimapPolicy::Mapq,
+            "random" => MultimapPolicy::Random,
+            "flag" => MultimapPolicy::Flag,
+            _ => unreachable!(),
+        }
+    }
+
+    // drops the records the policy doesn't consider, before any filter
+    // runs, and returns them
+    pub fn resolve(&self, alignments: &mut Vec<Record>) -> Vec<Record> {
+        match self {
+            MultimapPolicy::Strict => Vec::new(),
+            MultimapPolicy::Primary | MultimapPolicy::Mapq => {
+                split_off_records(alignments, |_, aln| {
+                    !aln.is_secondary() && !aln.is_supplementary()
+                })
+            }
+            MultimapPolicy::Random => select_best_pair(alignments),
+            MultimapPolicy::Flag => {
+                let dropped = split_off_records(alignments, |_, aln| {
+                    !aln.is_secondary() && !aln.is_supplementary()
+                });
+                if !dropped.is_empty() || alignments.iter().any(has_multimap_tag) {
+                    for aln in alignments.iter_mut() {
+                        aln.push_aux(b"ZM", &Aux::Integer(1));
+                    }
+                }
+                dropped
+            }
+        }
+    }
 }
 
-fn is_mitochondrial(alignments: &[Record], counter: &mut FragStats, mito_tid: u32) -> bool {
-    if alignments.first().expect("no alignments found").tid() == mito_tid as i32 {
-        counter.mito_skip += 1;
-        return true;
+// keeps the records for which the predicate, given the index of the record
+// in the read group, holds and returns the rest
+fn split_off_records<F>(alignments: &mut Vec<Record>, keep: F) -> Vec<Record>
+where
+    F: Fn(usize, &Record) -> bool,
+{
+    let mut dropped = Vec::new();
+    for (idx, aln) in std::mem::take(alignments).into_iter().enumerate() {
+        match keep(idx, &aln) {
+            true => alignments.push(aln),
+            false => dropped.push(aln),
+        };
     }
-    false
+
+    dropped
 }
 
-fn is_high_quality(alignments: &[Record], counter: &mut FragStats) -> bool {
-    let mut min_quality = u8::MAX;
-    for alignment in alignments {
-        min_quality = std::cmp::min(min_quality, alignment.mapq());
+// None if the tag is missing or isn't an integer, e.g. XS:A from some aligners
+fn aux_integer(aln: &Record, tag: &[u8]) -> Option<i64> {
+    match aln.aux(tag) {
+        Some(Aux::Integer(x)) => Some(x),
+        _ => None,
     }
+}
+
+// the bwa alternative hits, the only tag the strict policy checks
+fn has_alt_hits(aln: &Record) -> bool {
+    aln.aux(b"XA").is_some()
+}
 
-    if min_quality < MIN_MAPQ {
-        counter.mapq_skip += 1;
+fn is_flagged(aln: &Record) -> bool {
+    aux_integer(aln, b"ZM") == Some(1)
+}
+
+fn has_multimap_tag(aln: &Record) -> bool {
+    if has_alt_hits(aln) || aux_integer(aln, b"NH").unwrap_or(1) > 1 {
         return true;
     }
 
-    false
+    // suboptimal hit as good as the best one
+    match (aux_integer(aln, b"AS"), aux_integer(aln, b"XS")) {
+        (Some(best_score), Some(sub_score)) => sub_score >= best_score,
+        _ => false,
+    }
 }
 
-fn is_chimeric(alignments: &[Record], counter: &mut FragStats) -> bool {
-    assert_eq!(alignments.len(), 2);
-    let mut aln = alignments.first().unwrap();
-    let mut maln = alignments.last().unwrap();
-    if aln.is_reverse() {
-        std::mem::swap(&mut aln, &mut maln);
+// two records have to be the mates of a pair, a single-end read w/ a
+// secondary hit also comes in twos
+fn is_mate_pair(alignments: &[Record]) -> bool {
+    match alignments {
+        [aln, maln] => {
+            (aln.is_first_in_template() && maln.is_last_in_template())
+                || (aln.is_last_in_template() && maln.is_first_in_template())
+        }
+        _ => true,
     }
+}
 
-    // both reads mapped to different chromosome
-    if aln.tid() != maln.tid() {
-        counter.chimeric_tids += 1;
-        return true;
+// one of the best scoring hits, picked by the hash of the read name so that
+// reruns agree
+fn pick_best_hit(scores: &[i64], qname: &[u8]) -> usize {
+    let best_score = *scores.iter().max().expect("no hits to pick from");
+    let best_hits: Vec<usize> = (0..scores.len())
+        .filter(|idx| scores[*idx] == best_score)
+        .collect();
+
+    let mut hasher = StableHasher::new(0);
+    hasher.write(qname);
+    best_hits[hasher.finish() as usize % best_hits.len()]
+}
+
+// Pairs up the mates in the order of the records and keeps one of the best
+// scoring pairs, a single-end read keeps one of its best scoring records.
+// bwa reports the alternative hits only in the XA tag, its primary pair
+// already is a random best hit and is kept as is.
+fn select_best_pair(alignments: &mut Vec<Record>) -> Vec<Record> {
+    let mut dropped = split_off_records(alignments, |_, aln| !aln.is_supplementary());
+    if alignments.len() <= 1 {
+        return dropped;
     }
-    // if first read is reverse or mate is not reverse
-    // Note: we already swapped the first and second
-    if aln.is_reverse() || !maln.is_reverse() {
-        counter.chimeric_strand += 1;
-        return true;
+
+    let first_idxs: Vec<usize> = (0..alignments.len())
+        .filter(|idx| alignments[*idx].is_first_in_template())
+        .collect();
+    let last_idxs: Vec<usize> = (0..alignments.len())
+        .filter(|idx| alignments[*idx].is_last_in_template())
+        .collect();
+
+    if first_idxs.is_empty() && last_idxs.is_empty() {
+        let scores: Vec<i64> = alignments
+            .iter()
+            .map(|aln| aux_integer(aln, b"AS").unwrap_or(0))
+            .collect();
+        let keep = pick_best_hit(&scores, alignments[0].qname());
+        dropped.extend(split_off_records(alignments, |idx, _| idx == keep));
+        return dropped;
     }
-    // if mate-pairs mapped too far
-    if (aln.pos() - maln.pos()).abs() > MATE_MAX_DISTANCE {
-        counter.chimeric_max_distance += 1;
-        return true;
+
+    // the hits can't be paired up, the primary records are left to the filters
+    if first_idxs.len() != last_idxs.len() || first_idxs.len() * 2 != alignments.len() {
+        dropped.extend(split_off_records(alignments, |_, aln| !aln.is_secondary()));
+        return dropped;
     }
-    // if first is after second
-    if soft_clip_pos(aln) + MATE_MIN_DISTANCE > soft_clip_pos(maln) {
-        counter.chimeric_min_distance += 1;
-        return true;
+
+    let pair_scores: Vec<i64> = first_idxs
+        .iter()
+        .zip(last_idxs.iter())
+        .map(|(first, last)| {
+            aux_integer(&alignments[*first], b"AS").unwrap_or(0)
+                + aux_integer(&alignments[*last], b"AS").unwrap_or(0)
+        })
+        .collect();
+    let pair_idx = pick_best_hit(&pair_scores, alignments[0].qname());
+
+    let keep = (first_idxs[pair_idx], last_idxs[pair_idx]);
+    dropped.extend(split_off_records(alignments, |idx, _| {
+        idx == keep.0 || idx == keep.1
+    }));
+    dropped
+}
+
+pub struct MultimapFilter {
+    pub policy: MultimapPolicy,
+}
+
+impl FragmentFilter for MultimapFilter {
+    fn name(&self) -> &'static str {
+        "multimap"
+    }
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        let has_tag = alignments.iter().any(has_multimap_tag);
+        let is_tag_rejected = match self.policy {
+            MultimapPolicy::Strict => alignments.iter().any(has_alt_hits),
+            MultimapPolicy::Primary => has_tag,
+            _ => false,
+        };
+
+        if alignments.len() > 2 || !is_mate_pair(alignments) || is_tag_rejected {
+            counter.mm_reads += 1;
+            return Some(RejectReason::Multimap);
+        }
+
+        let is_multi = has_tag || alignments.iter().any(is_flagged);
+        if is_multi && self.policy != MultimapPolicy::Strict {
+            counter.mm_kept += 1;
+        }
+        None
+    }
+}
+
+// a contig name pattern, either the exact name or a regex matching
+// the full name
+fn contig_matches(name: &str, pattern: &Regex, pattern_str: &str) -> bool {
+    name == pattern_str || pattern.is_match(name)
+}
+
+fn compile_patterns(patterns: &[&str]) -> Result<Vec<(Regex, String)>, regex::Error> {
+    let mut regexes = Vec::with_capacity(patterns.len());
+    for pattern in patterns {
+        regexes.push((
+            Regex::new(&format!("^(?:{})$", pattern))?,
+            pattern.to_string(),
+        ));
+    }
+
+    Ok(regexes)
+}
+
+// flags the contig names matching any of the patterns
+pub fn match_contig_names(names: &[&str], patterns: &[&str]) -> Result<Vec<bool>, regex::Error> {
+    let patterns = compile_patterns(patterns)?;
+    Ok(names
+        .iter()
+        .map(|name| {
+            patterns
+                .iter()
+                .any(|(x, x_str)| contig_matches(name, x, x_str))
+        })
+        .collect())
+}
+
+fn match_contigs(header: &HeaderView, patterns: &[&str]) -> Result<Vec<bool>, regex::Error> {
+    let names: Vec<&str> = header
+        .target_names()
+        .into_iter()
+        .map(|name| std::str::from_utf8(name).expect("can't parse contig name"))
+        .collect();
+    match_contig_names(&names, patterns)
+}
+
+// Skips the read groups with an alignment on the mitochondrial chromosome,
+// on an excluded contig, e.g. decoys or spike-in genomes, or on a contig
+// outside the include list. The mito reads keep their own reject reason.
+#[derive(Clone)]
+pub struct ContigFilter {
+    skip_tids: Vec<bool>,
+    mito_tids: Vec<bool>,
+}
+
+impl ContigFilter {
+    pub fn new(
+        header: &HeaderView,
+        include: &[&str],
+        exclude: &[&str],
+        mitostr: Option<&str>,
+    ) -> Result<ContigFilter, regex::Error> {
+        let mito_tids = match mitostr {
+            Some(mitostr) => match_contigs(header, &[mitostr])?,
+            None => vec![false; header.target_count() as usize],
+        };
+        let is_excluded: Vec<bool> = match_contigs(header, exclude)?
+            .into_iter()
+            .zip(mito_tids.iter())
+            .map(|(is_excluded, is_mito)| is_excluded || *is_mito)
+            .collect();
+        let skip_tids: Vec<bool> = match include.is_empty() {
+            true => is_excluded,
+            false => match_contigs(header, include)?
+                .into_iter()
+                .zip(is_excluded)
+                .map(|(is_included, is_excluded)| !is_included || is_excluded)
+                .collect(),
+        };
+
+        let num_skipped = skip_tids.iter().filter(|x| **x).count();
+        info!(
+            "Skipping {} out of {} contigs",
+            num_skipped,
+            skip_tids.len()
+        );
+        Ok(ContigFilter {
+            skip_tids,
+            mito_tids,
+        })
+    }
+
+    // --include and --exclude lists, the mitochondrial chromosome
+    // (--mitostr) is always skipped in addition to the exclude list
+    pub fn from_clap(
+        sub_m: &ArgMatches,
+        header: &HeaderView,
+        mitostr: Option<&str>,
+    ) -> Result<Option<ContigFilter>, regex::Error> {
+        let include: Vec<&str> = sub_m
+            .values_of("include")
+            .map_or(Vec::new(), |x| x.collect());
+        let exclude: Vec<&str> = sub_m
+            .values_of("exclude")
+            .map_or(Vec::new(), |x| x.collect());
+
+        if include.is_empty() && exclude.is_empty() && mitostr.is_none() {
+            return Ok(None);
+        }
+        info!(
+            "Contig include list: {:?}, exclude list: {:?}, mito: {:?}",
+            include, exclude, mitostr
+        );
+        Ok(Some(ContigFilter::new(
+            header, &include, &exclude, mitostr,
+        )?))
+    }
+
+    pub fn is_skipped(&self, tid: i32) -> bool {
+        tid >= 0 && self.skip_tids.get(tid as usize).copied().unwrap_or(false)
     }
 
-    false
+    pub fn is_mito(&self, tid: i32) -> bool {
+        tid >= 0 && self.mito_tids.get(tid as usize).copied().unwrap_or(false)
+    }
 }
 
-fn has_barcode_tag(alignments: &[Record], counter: &mut FragStats) -> bool {
-    for aln in alignments {
-        if aln.aux(b"CB").is_none() {
-            counter.cb_skip += 1;
-            return false;
+impl FragmentFilter for ContigFilter {
+    fn name(&self) -> &'static str {
+        "contig"
+    }
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        if !alignments.iter().any(|aln| self.is_skipped(aln.tid())) {
+            return None;
         }
+
+        counter.contig_skip += 1;
+        match alignments.iter().any(|aln| self.is_mito(aln.tid())) {
+            true => Some(RejectReason::Mito),
+            false => Some(RejectReason::Contig),
+        }
+    }
+}
+
+// Read groups on the spike-in genome, these are counted per barcode by
+// the caller and never written as fragments.
+pub struct SpikeInFilter {
+    spikein_tids: Vec<bool>,
+}
+
+impl SpikeInFilter {
+    pub fn new(header: &HeaderView, patterns: &[&str]) -> Result<SpikeInFilter, regex::Error> {
+        let spikein_tids = match_contigs(header, patterns)?;
+        info!(
+            "Found {} spike-in contigs",
+            spikein_tids.iter().filter(|x| **x).count()
+        );
+        Ok(SpikeInFilter { spikein_tids })
     }
 
-    true
+    pub fn from_clap(
+        sub_m: &ArgMatches,
+        header: &HeaderView,
+    ) -> Result<Option<SpikeInFilter>, regex::Error> {
+        match sub_m.values_of("spikein") {
+            Some(patterns) => {
+                let patterns: Vec<&str> = patterns.collect();
+                info!("Spike-in contig list: {:?}", patterns);
+                Ok(Some(SpikeInFilter::new(header, &patterns)?))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
-pub fn callback<'a>(
-    alignments: &'a Vec<Record>,
-    mut counter: &mut FragStats,
-    stats_only: bool,
-    is_tenx: bool,
-    mito_tid: u32,
-) -> Option<(&'a Record, &'a Record)> {
-    if is_unmapped(&alignments, &mut counter)
-        || is_multi_mapping(&alignments, &mut counter)
-        || is_mitochondrial(&alignments, &mut counter, mito_tid)
-        || is_high_quality(&alignments, &mut counter)
-        || is_chimeric(&alignments, &mut counter)
-        || (is_tenx && !has_barcode_tag(&alignments, &mut counter))
-        || stats_only
-    {
-        return None;
+impl FragmentFilter for SpikeInFilter {
+    fn name(&self) -> &'static str {
+        "spikein"
     }
 
-    let aln = alignments.first().unwrap();
-    let maln = alignments.last().unwrap();
-    let frag = match alignments.first().unwrap().is_reverse() {
-        true => (maln, aln),
-        false => (aln, maln),
-    };
+    fn reject(&self, alignments: &[Record], _counter: &mut FragStats) -> Option<RejectReason> {
+        let tid = alignments.first().expect("no alignments found").tid();
+        if tid >= 0
+            && self
+                .spikein_tids
+                .get(tid as usize)
+                .copied()
+                .unwrap_or(false)
+        {
+            return Some(RejectReason::SpikeIn);
+        }
+        None
+    }
+}
+
+pub struct MapqFilter {
+    pub min_mapq: u8,
+}
+
+impl FragmentFilter for MapqFilter {
+    fn name(&self) -> &'static str {
+        "mapq"
+    }
 
-    Some(frag)
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        let mut min_quality = u8::MAX;
+        for alignment in alignments {
+            min_quality = std::cmp::min(min_quality, alignment.mapq());
+        }
+
+        if min_quality < self.min_mapq {
+            counter.mapq_skip += 1;
+            return Some(RejectReason::Mapq);
+        }
+
+        None
+    }
+}
+
+pub struct ChimericFilter;
+
+impl FragmentFilter for ChimericFilter {
+    fn name(&self) -> &'static str {
+        "chimeric"
+    }
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        // a half-fragment has no mate to check against
+        if alignments.len() == 1 {
+            return None;
+        }
+
+        assert_eq!(alignments.len(), 2);
+        let mut aln = alignments.first().unwrap();
+        let mut maln = alignments.last().unwrap();
+        if aln.is_reverse() {
+            std::mem::swap(&mut aln, &mut maln);
+        }
+
+        // both reads mapped to different chromosome
+        if aln.tid() != maln.tid() {
+            counter.chimeric_tids += 1;
+            return Some(RejectReason::ChimericTid);
+        }
+        // if first read is reverse or mate is not reverse
+        // Note: we already swapped the first and second
+        if aln.is_reverse() || !maln.is_reverse() {
+            counter.chimeric_strand += 1;
+            return Some(RejectReason::ChimericStrand);
+        }
+        // if mate-pairs mapped too far
+        if (aln.pos() - maln.pos()).abs() > MATE_MAX_DISTANCE {
+            counter.chimeric_max_distance += 1;
+            return Some(RejectReason::TooFar);
+        }
+        // if first is after second
+        if soft_clip_pos(aln) + MATE_MIN_DISTANCE > soft_clip_pos(maln) {
+            counter.chimeric_min_distance += 1;
+            return Some(RejectReason::TooClose);
+        }
+
+        None
+    }
+}
+
+pub struct BarcodeTagFilter;
+
+impl FragmentFilter for BarcodeTagFilter {
+    fn name(&self) -> &'static str {
+        "no_cb"
+    }
+
+    fn reject(&self, alignments: &[Record], counter: &mut FragStats) -> Option<RejectReason> {
+        for aln in alignments {
+            if aln.aux(b"CB").is_none() {
+                counter.cb_skip += 1;
+                return Some(RejectReason::NoCb);
+            }
+        }
+
+        None
+    }
+}
+
+// settings of the built-in filters
+pub struct FilterConfig {
+    pub is_tenx: bool,
+    pub keep_orphans: bool,
+    pub multimap: MultimapPolicy,
+    pub spikein: Option<SpikeInFilter>,
+    pub contigs: Option<ContigFilter>,
+    pub min_mapq: u8,
+}
+
+impl Default for FilterConfig {
+    fn default() -> FilterConfig {
+        FilterConfig {
+            is_tenx: false,
+            keep_orphans: false,
+            multimap: MultimapPolicy::Strict,
+            spikein: None,
+            contigs: None,
+            min_mapq: MIN_MAPQ,
+        }
+    }
+}
+
+impl FilterConfig {
+    // --multimap and --mapq, the contig and spike-in filters need the header
+    pub fn from_clap(sub_m: &ArgMatches) -> FilterConfig {
+        let multimap = MultimapPolicy::from_name(sub_m.value_of("multimap").unwrap_or("strict"));
+        let min_mapq = match sub_m.value_of("mapq") {
+            Some(mapq) => mapq.parse().expect("can't parse min MAPQ"),
+            None => MIN_MAPQ,
+        };
+        info!(
+            "Using {:?} multimapper policy w/ min MAPQ {}",
+            multimap, min_mapq
+        );
+
+        FilterConfig {
+            keep_orphans: sub_m.is_present("orphans"),
+            multimap,
+            min_mapq,
+            ..Default::default()
+        }
+    }
+}
+
+// Ordered list of filters, a read group is rejected by the first filter
+// which fails and is a fragment if it passes all of them.
+#[derive(Default)]
+pub struct FilterChain {
+    keep_orphans: bool,
+    multimap: MultimapPolicy,
+    filters: Vec<Box<dyn FragmentFilter>>,
+}
+
+impl FilterChain {
+    // the default chain, the chimeric filter needs exactly two alignments
+    // and has to come after the unmapped and multimapping filters, the
+    // spike-in reads are taken before the contig include list drops them
+    pub fn from_config(config: FilterConfig) -> FilterChain {
+        let mut chain = FilterChain::default().with(UnmappedFilter {
+            keep_orphans: config.keep_orphans,
+        });
+        chain.keep_orphans = config.keep_orphans;
+        chain.multimap = config.multimap;
+        if let Some(spikein) = config.spikein {
+            chain = chain.with(spikein);
+        }
+        chain = chain.with(MultimapFilter {
+            policy: config.multimap,
+        });
+        if let Some(contigs) = config.contigs {
+            chain = chain.with(contigs);
+        }
+        chain = chain
+            .with(MapqFilter {
+                min_mapq: config.min_mapq,
+            })
+            .with(ChimericFilter);
+        if config.is_tenx {
+            chain = chain.with(BarcodeTagFilter);
+        }
+
+        chain
+    }
+
+    pub fn with<F: FragmentFilter + 'static>(mut self, filter: F) -> FilterChain {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.filters.iter().map(|x| x.name()).collect()
+    }
+
+    // empty counters w/ the per filter skips in the order of the chain
+    pub fn new_stats(&self) -> FragStats {
+        FragStats::with_filters(&self.names())
+    }
+
+    // has to be called on the read group before apply, drops the records
+    // of the multimappers not considered and the unmapped mate of an orphan
+    // and returns them w/ the reason, for the rejects output
+    pub fn prepare(&self, alignments: &mut Vec<Record>) -> DroppedRecords {
+        let mut dropped = Vec::new();
+        let multimaps = self.multimap.resolve(alignments);
+        if !multimaps.is_empty() {
+            dropped.push((RejectReason::Multimap, multimaps));
+        }
+
+        if self.keep_orphans && alignments.len() == 2 {
+            let num_mapped = alignments.iter().filter(|x| !x.is_unmapped()).count();
+            if num_mapped == 1 {
+                let mates = split_off_records(alignments, |_, x| !x.is_unmapped());
+                dropped.push((RejectReason::Orphan, mates));
+            }
+        }
+
+        dropped
+    }
+
+    pub fn apply<'a>(
+        &self,
+        alignments: &'a [Record],
+        counter: &mut FragStats,
+    ) -> Result<FragmentAlignments<'a>, RejectReason> {
+        for filter in &self.filters {
+            if let Some(reason) = filter.reject(alignments, counter) {
+                counter.add_filter_skip(filter.name());
+                return Err(reason);
+            }
+        }
+
+        let aln = alignments.first().unwrap();
+        if alignments.len() == 1 {
+            counter.half_frags += 1;
+            return Ok(FragmentAlignments::Insertion(aln));
+        }
+
+        let maln = alignments.last().unwrap();
+        let frag = match aln.is_reverse() {
+            true => FragmentAlignments::Pair(maln, aln),
+            false => FragmentAlignments::Pair(aln, maln),
+        };
+
+        Ok(frag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::{Header, HeaderRecord};
+    use rust_htslib::bam::record::{Cigar, CigarString};
+
+    const PAIRED: u16 = 0x1;
+    const UNMAPPED: u16 = 0x4;
+    const REVERSE: u16 = 0x10;
+    const FIRST: u16 = 0x40;
+    const LAST: u16 = 0x80;
+
+    fn record(tid: i32, pos: i64, flags: u16, mapq: u8) -> Record {
+        let mut rec = Record::new();
+        let cigar = CigarString(vec![Cigar::Match(50)]);
+        rec.set(b"read", Some(&cigar), &[b'A'; 50], &[30; 50]);
+        rec.set_tid(tid);
+        rec.set_pos(pos);
+        rec.set_flags(flags);
+        rec.set_mapq(mapq);
+        rec
+    }
+
+    fn pair(tid: i32, pos: i64, mate_pos: i64) -> Vec<Record> {
+        vec![
+            record(tid, pos, PAIRED | FIRST, 60),
+            record(tid, mate_pos, PAIRED | LAST | REVERSE, 60),
+        ]
+    }
+
+    fn header_view() -> HeaderView {
+        let mut header = Header::new();
+        for name in &["chr1", "chrM", "chrUn", "chr2"] {
+            let mut record = HeaderRecord::new(b"SQ");
+            record.push_tag(b"SN", name);
+            record.push_tag(b"LN", &100_000);
+            header.push_record(&record);
+        }
+        HeaderView::from_header(&header)
+    }
+
+    fn chain() -> FilterChain {
+        let header = header_view();
+        FilterChain::from_config(FilterConfig {
+            contigs: ContigFilter::new(&header, &[], &["chrUn"], Some("chrM")).ok(),
+            ..Default::default()
+        })
+    }
+
+    fn reject_reason(alignments: &[Record]) -> Option<RejectReason> {
+        let chain = chain();
+        let mut counter = chain.new_stats();
+        chain.apply(alignments, &mut counter).err()
+    }
+
+    #[test]
+    fn passes_a_proper_pair() {
+        let alignments = pair(0, 100, 300);
+        let chain = chain();
+        let mut counter = chain.new_stats();
+        match chain.apply(&alignments, &mut counter) {
+            Ok(FragmentAlignments::Pair(aln, maln)) => {
+                assert_eq!((aln.pos(), maln.pos()), (100, 300))
+            }
+            _ => panic!("proper pair rejected"),
+        };
+    }
+
+    #[test]
+    fn reject_reasons() {
+        let mut orphan = pair(0, 100, 300);
+        orphan[1].set_flags(PAIRED | LAST | UNMAPPED);
+        assert_eq!(reject_reason(&orphan), Some(RejectReason::Orphan));
+
+        let mut unmapped = pair(0, 100, 300);
+        unmapped[0].set_flags(PAIRED | FIRST | UNMAPPED);
+        unmapped[1].set_flags(PAIRED | LAST | UNMAPPED);
+        assert_eq!(reject_reason(&unmapped), Some(RejectReason::Unmapped));
+
+        let mut multimap = pair(0, 100, 300);
+        multimap.push(record(0, 5_000, PAIRED | FIRST | 0x100, 0));
+        assert_eq!(reject_reason(&multimap), Some(RejectReason::Multimap));
+
+        assert_eq!(reject_reason(&pair(1, 100, 300)), Some(RejectReason::Mito));
+        assert_eq!(
+            reject_reason(&pair(2, 100, 300)),
+            Some(RejectReason::Contig)
+        );
+
+        let mut low_mapq = pair(0, 100, 300);
+        low_mapq[1].set_mapq(MIN_MAPQ - 1);
+        assert_eq!(reject_reason(&low_mapq), Some(RejectReason::Mapq));
+
+        let mut chimeric = pair(0, 100, 300);
+        chimeric[1].set_tid(3);
+        assert_eq!(reject_reason(&chimeric), Some(RejectReason::ChimericTid));
+        chimeric[1].set_tid(0);
+        chimeric[1].set_flags(PAIRED | LAST);
+        assert_eq!(reject_reason(&chimeric), Some(RejectReason::ChimericStrand));
+
+        let too_far = pair(0, 100, 100 + MATE_MAX_DISTANCE + 1);
+        assert_eq!(reject_reason(&too_far), Some(RejectReason::TooFar));
+        let too_close = pair(0, 100, 50);
+        assert_eq!(reject_reason(&too_close), Some(RejectReason::TooClose));
+    }
+
+    #[test]
+    fn counts_skips_in_chain_order() {
+        let chain = chain();
+        let mut counter = chain.new_stats();
+        let mut low_mapq = pair(0, 100, 300);
+        low_mapq[0].set_mapq(0);
+        assert!(chain.apply(&low_mapq, &mut counter).is_err());
+        assert!(chain.apply(&pair(1, 100, 300), &mut counter).is_err());
+
+        let names: Vec<&str> = counter.filter_skips.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, chain.names());
+        let skips: Vec<usize> = counter
+            .filter_skips
+            .iter()
+            .map(|(_, count)| *count)
+            .collect();
+        assert_eq!(skips, vec![0, 0, 1, 1, 0]);
+    }
+
+    fn with_score(mut aln: Record, score: i64) -> Record {
+        aln.push_aux(b"AS", &Aux::Integer(score));
+        aln
+    }
+
+    #[test]
+    fn rejects_a_single_end_read_w_a_secondary_hit() {
+        let alignments = vec![record(0, 100, 0, 60), record(0, 5_000, 0x100, 0)];
+        assert_eq!(reject_reason(&alignments), Some(RejectReason::Multimap));
+    }
+
+    #[test]
+    fn random_policy_keeps_a_best_hit() {
+        let mut single_end = vec![
+            with_score(record(0, 100, 0, 60), 40),
+            with_score(record(0, 5_000, 0x100, 0), 50),
+            with_score(record(0, 9_000, 0x100, 0), 30),
+        ];
+        let dropped = MultimapPolicy::Random.resolve(&mut single_end);
+        assert_eq!(dropped.len(), 2);
+        assert_eq!(single_end[0].pos(), 5_000);
+
+        let mut paired = pair(0, 100, 300);
+        paired.push(record(0, 5_000, PAIRED | FIRST | 0x100, 0));
+        let dropped = MultimapPolicy::Random.resolve(&mut paired);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(paired.len(), 2);
+    }
+
+    #[test]
+    fn flag_policy_marks_the_kept_pair() {
+        let mut alignments = pair(0, 100, 300);
+        alignments.push(record(0, 5_000, PAIRED | FIRST | 0x100, 0));
+        let dropped = MultimapPolicy::Flag.resolve(&mut alignments);
+        assert_eq!(dropped.len(), 1);
+        assert!(alignments.iter().all(is_flagged));
+
+        let filter = MultimapFilter {
+            policy: MultimapPolicy::Flag,
+        };
+        let mut counter = FragStats::default();
+        assert_eq!(filter.reject(&alignments, &mut counter), None);
+        assert_eq!(counter.mm_kept, 1);
+    }
 }
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::schema::cb_from_string;
+
+// barcode -> group assignment from a two column TSV, the group names
+// are kept in the order they first show up
+pub struct BarcodeGroups {
+    pub names: Vec<String>,
+    pub cb_groups: HashMap<u64, usize>,
+}
+
+impl BarcodeGroups {
+    pub fn from_file(path: &str) -> Result<BarcodeGroups, Box<dyn Error>> {
+        let file_path = Path::new(path)
+            .canonicalize()
+            .expect("can't find absolute path of input groups file");
+        info!("Found barcode groups file: {:?}", file_path);
+
+        let mut names: Vec<String> = Vec::new();
+        let mut name_ids: HashMap<String, usize> = HashMap::new();
+        let mut cb_groups: HashMap<u64, usize> = HashMap::new();
+        let mut is_first_line = true;
+        for (line_num, line) in BufReader::new(File::open(file_path)?).lines().enumerate() {
+            let line = line?;
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.split('\t').collect();
+            if toks.len() != 2 {
+                return Err(format!(
+                    "groups file needs barcode and group columns, line {}: {}",
+                    line_num + 1,
+                    line
+                )
+                .into());
+            }
+
+            // only the first line can be a header
+            let cb = match (cb_from_string(toks[0].trim()), is_first_line) {
+                (Ok(cb), _) => cb,
+                (Err(_), true) => {
+                    is_first_line = false;
+                    continue;
+                }
+                (Err(err), false) => {
+                    return Err(format!(
+                        "can't parse barcode on line {}: {} ({})",
+                        line_num + 1,
+                        toks[0],
+                        err
+                    )
+                    .into())
+                }
+            };
+            is_first_line = false;
+
+            let group_id = *name_ids
+                .entry(toks[1].trim().to_string())
+                .or_insert_with(|| {
+                    names.push(toks[1].trim().to_string());
+                    names.len() - 1
+                });
+            cb_groups.insert(cb, group_id);
+        }
+
+        info!(
+            "Found {} barcodes in {} groups",
+            cb_groups.len().to_formatted_string(&Locale::en),
+            names.len()
+        );
+        Ok(BarcodeGroups { names, cb_groups })
+    }
+
+    // group name safe to use as a file name, w/o any path separator and
+    // w/o a leading dot so it can't be hidden or point outside the directory
+    pub fn file_name(&self, group_id: usize) -> String {
+        self.names[group_id]
+            .chars()
+            .enumerate()
+            .map(
+                |(idx, x)| match x.is_ascii_alphanumeric() || x == '-' || (x == '.' && idx > 0) {
+                    true => x,
+                    false => '_',
+                },
+            )
+            .collect()
+    }
+
+    // file names of all the groups, two groups (or a group and one of the
+    // reserved names) ending up w/ the same file name is an error
+    pub fn file_names(&self, reserved: &[&str]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut used: HashMap<String, &str> =
+            reserved.iter().map(|x| (x.to_string(), *x)).collect();
+
+        let mut file_names = Vec::with_capacity(self.names.len());
+        for (group_id, name) in self.names.iter().enumerate() {
+            let file_name = self.file_name(group_id);
+            if let Some(other) = used.insert(file_name.clone(), name.as_str()) {
+                return Err(format!(
+                    "groups {:?} and {:?} both write to the file name {:?}",
+                    other, name, file_name
+                )
+                .into());
+            }
+            file_names.push(file_name);
+        }
+
+        Ok(file_names)
+    }
+}
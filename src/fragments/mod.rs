@@ -1,3 +1,13 @@
+pub mod aggregate;
+pub mod complexity;
 pub mod count_stats;
+pub mod coverage;
+pub mod demux;
+pub mod doublets;
 pub mod filter;
+pub mod groups;
+pub mod multiplets;
+pub mod pseudobulk;
 pub mod schema;
+pub mod spikein;
+pub mod subsample;
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use clap::ArgMatches;
+use itertools::Itertools;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::doublets::{adjust_pvalues, poisson_upper_tail};
+use crate::fragments::schema::{cb_to_string, Fragment, FragmentFile};
+
+// fragments shared by more barcodes are high copy loci, not bead multiplets
+const MAX_SHARED_CBS: usize = 6;
+
+// union find over the cells, w/ the root as the barcode w/ most fragments
+struct Merger {
+    parents: Vec<usize>,
+}
+
+impl Merger {
+    fn new(num_cells: usize) -> Merger {
+        Merger {
+            parents: (0..num_cells).collect(),
+        }
+    }
+
+    fn find(&mut self, idx: usize) -> usize {
+        let mut root = idx;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+
+        let mut idx = idx;
+        while self.parents[idx] != root {
+            let next = self.parents[idx];
+            self.parents[idx] = root;
+            idx = next;
+        }
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize, num_frags: &[usize]) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+
+        // ties go to the smaller barcode, to be independent of the pair order
+        match (num_frags[a], b) > (num_frags[b], a) {
+            true => self.parents[b] = a,
+            false => self.parents[a] = b,
+        };
+    }
+}
+
+fn count_cb_frags(path: &Path) -> Result<HashMap<u64, usize>, Box<dyn Error>> {
+    let mut cb_frags: HashMap<u64, usize> = HashMap::new();
+    for frag in FragmentFile::new(BufReader::new(File::open(path)?)) {
+        *cb_frags.entry(frag.cb).or_insert(0) += 1;
+    }
+
+    Ok(cb_frags)
+}
+
+// number of fragments w/ identical coordinates for every pair of cells
+fn count_shared_pairs(
+    path: &Path,
+    cell_ids: &HashMap<u64, usize>,
+) -> Result<HashMap<(usize, usize), usize>, Box<dyn Error>> {
+    let mut shared_pairs: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut joint_class = HashMap::with_capacity(500);
+    for (chr, chr_group) in FragmentFile::new(BufReader::new(File::open(path)?))
+        .group_by(|frag| frag.chr)
+        .into_iter()
+    {
+        print!("\rWorking on Chromosome: {}", chr);
+        std::io::stdout().flush().expect("Can't flush output");
+
+        for frag in chr_group {
+            if let Some(idx) = cell_ids.get(&frag.cb) {
+                joint_class
+                    .entry(Range {
+                        start: frag.start,
+                        end: frag.end,
+                    })
+                    .or_insert_with(Vec::new)
+                    .push(*idx);
+            }
+        }
+
+        for (_, mut cells) in joint_class.drain() {
+            cells.sort_unstable();
+            cells.dedup();
+            if cells.len() < 2 || cells.len() > MAX_SHARED_CBS {
+                continue;
+            }
+
+            for (a, b) in cells.into_iter().tuple_combinations() {
+                *shared_pairs.entry((a, b)).or_insert(0) += 1;
+            }
+        }
+    }
+    println!();
+
+    Ok(shared_pairs)
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+
+    let min_frags: usize = sub_m
+        .value_of("minfrags")
+        .unwrap_or("1000")
+        .parse()
+        .expect("can't parse min fragments");
+    let min_shared: usize = sub_m
+        .value_of("minshared")
+        .unwrap_or("3")
+        .parse()
+        .expect("can't parse min shared fragments");
+    let max_qvalue: f64 = sub_m
+        .value_of("qvalue")
+        .unwrap_or("0.01")
+        .parse()
+        .expect("can't parse q-value threshold");
+
+    let cb_frags = count_cb_frags(&ibed_file_path)?;
+    let mut cells: Vec<u64> = cb_frags
+        .iter()
+        .filter(|(_, num_frags)| **num_frags >= min_frags)
+        .map(|(cb, _)| *cb)
+        .collect();
+    cells.sort_unstable();
+    if cells.is_empty() {
+        return Err(format!("no cells w/ >= {} fragments found", min_frags).into());
+    }
+    let num_frags: Vec<usize> = cells.iter().map(|cb| cb_frags[cb]).collect();
+    let cell_ids: HashMap<u64, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(idx, cb)| (*cb, idx))
+        .collect();
+    info!(
+        "Found {} cells w/ >= {} fragments",
+        cells.len().to_formatted_string(&Locale::en),
+        min_frags
+    );
+
+    let shared_pairs = count_shared_pairs(&ibed_file_path, &cell_ids)?;
+
+    // the shared fragments are spread over the pairs of cells proportional
+    // to the product of their fragment counts
+    let total_shared: usize = shared_pairs.values().sum();
+    let sum_frags: f64 = num_frags.iter().map(|x| *x as f64).sum();
+    let sum_sq_frags: f64 = num_frags.iter().map(|x| *x as f64 * *x as f64).sum();
+    let sum_pair_frags = (sum_frags * sum_frags - sum_sq_frags) / 2.0;
+
+    let mut pairs: Vec<((usize, usize), usize)> = shared_pairs
+        .into_iter()
+        .filter(|(_, num_shared)| *num_shared >= min_shared)
+        .collect();
+    pairs.sort_unstable();
+    let pvalues: Vec<f64> = pairs
+        .iter()
+        .map(|((a, b), num_shared)| {
+            let expected =
+                total_shared as f64 * num_frags[*a] as f64 * num_frags[*b] as f64 / sum_pair_frags;
+            poisson_upper_tail(*num_shared as u64, expected)
+        })
+        .collect();
+    let qvalues = adjust_pvalues(&pvalues);
+
+    let mut merger = Merger::new(cells.len());
+    let mut num_multiplet_pairs = 0;
+    for (idx, ((a, b), _)) in pairs.iter().enumerate() {
+        if qvalues[idx] < max_qvalue {
+            num_multiplet_pairs += 1;
+            merger.union(*a, *b, &num_frags);
+        }
+    }
+
+    let translation: HashMap<u64, u64> = (0..cells.len())
+        .filter_map(|idx| match merger.find(idx) {
+            root if root != idx => Some((cells[idx], cells[root])),
+            _ => None,
+        })
+        .collect();
+    info!(
+        "Found {} significant barcode pairs, merging {} barcodes",
+        num_multiplet_pairs.to_formatted_string(&Locale::en),
+        translation.len().to_formatted_string(&Locale::en)
+    );
+
+    let out_path = Path::new(sub_m.value_of("output").expect("can't find output flag"));
+    info!("Creating barcode translation file: {:?}", out_path);
+    let mut file = BufWriter::new(File::create(out_path)?);
+    writeln!(file, "barcode\tmerged_barcode\tnum_fragments\tis_multiplet")?;
+    for (idx, cb) in cells.iter().enumerate() {
+        let merged_cb = translation.get(cb).unwrap_or(cb);
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            cb_to_string(*cb)?,
+            cb_to_string(*merged_cb)?,
+            num_frags[idx],
+            merged_cb != cb
+        )?;
+    }
+
+    // fragments of the merged barcodes are deduplicated again
+    if sub_m.is_present("obed") {
+        let mut obed_file = carina::file::bufwriter_from_clap(sub_m, "obed")?;
+        let input_bed = BufReader::new(File::open(&ibed_file_path)?);
+
+        let mut num_written = 0;
+        let mut joint_class = HashMap::with_capacity(500);
+        for (chr, chr_group) in FragmentFile::new(input_bed)
+            .group_by(|frag| frag.chr)
+            .into_iter()
+        {
+            for frag in chr_group {
+                joint_class
+                    .entry(Range {
+                        start: frag.start,
+                        end: frag.end,
+                    })
+                    .or_insert_with(Vec::new)
+                    .push(*translation.get(&frag.cb).unwrap_or(&frag.cb));
+            }
+
+            let mut ranges: Vec<(Range<u64>, Vec<u64>)> = joint_class.drain().collect();
+            ranges.sort_unstable_by_key(|(range, _)| (range.start, range.end));
+            for (range, mut cbs) in ranges {
+                cbs.sort_unstable();
+                cbs.dedup();
+                for cb in cbs {
+                    let frag = Fragment {
+                        start: range.start,
+                        end: range.end,
+                        cb,
+                        chr,
+                    };
+                    frag.write(&mut obed_file, "binary")?;
+                    num_written += 1;
+                }
+            }
+        }
+
+        info!(
+            "Wrote {} fragments w/ the merged barcodes",
+            num_written.to_formatted_string(&Locale::en)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::coverage::{ChromSizes, Coverage};
+use crate::fragments::groups::BarcodeGroups;
+use crate::fragments::schema::FragmentFile;
+use crate::fragments::spikein::SpikeInCounts;
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let input_bed = BufReader::new(File::open(ibed_file_path)?);
+
+    let groups =
+        BarcodeGroups::from_file(sub_m.value_of("groups").expect("can't find groups flag"))?;
+    let out_dir = PathBuf::from(
+        sub_m
+            .value_of("odir")
+            .expect("can't find output directory flag"),
+    );
+    std::fs::create_dir_all(&out_dir)?;
+
+    // splitting the fragments into the group files in a single pass
+    let file_names = groups.file_names(&[])?;
+    let mut group_files = Vec::with_capacity(groups.names.len());
+    for file_name in &file_names {
+        let file_path = out_dir.join(format!("{}.fragments.bed", file_name));
+        info!("Creating output BED file: {:?}", file_path);
+        group_files.push(BufWriter::new(File::create(file_path)?));
+    }
+
+    let mut num_frags = 0;
+    let mut no_group_skip = 0;
+    let mut group_frags = vec![0; groups.names.len()];
+    let mut group_cells: Vec<HashSet<u64>> = vec![HashSet::new(); groups.names.len()];
+    for frag in FragmentFile::new(input_bed) {
+        num_frags += 1;
+        if num_frags % crate::configs::MIL == 0 {
+            print!(
+                "\rDone processing {}M fragments",
+                num_frags / crate::configs::MIL
+            );
+            std::io::stdout().flush().expect("Can't flush output");
+        }
+
+        match groups.cb_groups.get(&frag.cb) {
+            Some(group_id) => {
+                frag.write(&mut group_files[*group_id], "binary")?;
+                group_frags[*group_id] += 1;
+                group_cells[*group_id].insert(frag.cb);
+            }
+            None => no_group_skip += 1,
+        };
+    }
+    println!();
+    for mut file in group_files {
+        file.flush()?;
+    }
+    info!(
+        "Skipped {} out of {} fragments w/o a group",
+        no_group_skip.to_formatted_string(&Locale::en),
+        num_frags.to_formatted_string(&Locale::en)
+    );
+
+    let chrom_sizes = ChromSizes::from_clap(sub_m)?;
+    let bin_size: u64 = sub_m
+        .value_of("binsize")
+        .unwrap_or("50")
+        .parse()
+        .expect("can't parse bin size");
+    let mode = sub_m.value_of("mode").unwrap_or("fragment");
+    let norm = sub_m.value_of("norm").unwrap_or("cpm");
+    let format = sub_m.value_of("format").unwrap_or("bigwig");
+    let spikein = match sub_m.value_of("spikein") {
+        Some(path) => Some(SpikeInCounts::from_file(Path::new(path))?),
+        None => None,
+    };
+    let extension = match format {
+        "bigwig" => "bw",
+        _ => "bedGraph",
+    };
+
+    let summary_path = out_dir.join("summary.tsv");
+    info!("Creating summary file: {:?}", summary_path);
+    let mut summary_file = BufWriter::new(File::create(summary_path)?);
+    writeln!(
+        summary_file,
+        "group\tnum_cells\tnum_fragments\tspikein_reads\tscale_factor"
+    )?;
+
+    // one group in memory at a time while making the tracks
+    for group_id in 0..groups.names.len() {
+        let file_name = &file_names[group_id];
+        info!("Working on group: {}", groups.names[group_id]);
+
+        let input_bed = BufReader::new(File::open(
+            out_dir.join(format!("{}.fragments.bed", file_name)),
+        )?);
+        let mut coverage = Coverage::new(&chrom_sizes, bin_size, mode);
+        for frag in FragmentFile::new(input_bed) {
+            coverage.add(&frag)?;
+        }
+
+        let spikein_reads: Option<usize> = spikein.as_ref().map(|counts| {
+            group_cells[group_id]
+                .iter()
+                .map(|cb| counts.cb_count(*cb))
+                .sum()
+        });
+        let scale = match coverage.total() == 0 || (norm == "spikein" && spikein_reads == Some(0)) {
+            true => {
+                warn!("Nothing to normalize w/, writing an empty track");
+                0.0
+            }
+            false => coverage.scale(norm, spikein_reads)?,
+        };
+
+        let out_path = out_dir.join(format!("{}.{}", file_name, extension));
+        coverage.write_track(&chrom_sizes, scale, &out_path, format)?;
+
+        writeln!(
+            summary_file,
+            "{}\t{}\t{}\t{}\t{}",
+            groups.names[group_id],
+            group_cells[group_id].len(),
+            group_frags[group_id],
+            spikein_reads.map_or("NA".to_string(), |x| x.to_string()),
+            scale
+        )?;
+    }
+
+    Ok(())
+}
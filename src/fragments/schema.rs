@@ -1,8 +1,10 @@
 use std::error::Error;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::io::Write;
 use std::io::{BufReader, BufWriter};
+use std::path::Path;
 
 use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::Record;
@@ -10,7 +12,92 @@ use rust_htslib::bam::Record;
 use crate::configs::{CB_LENGTH, TN5_LEFT_OFFSET, TN5_RIGHT_OFFSET};
 use serde::{Deserialize, Serialize};
 
-use carina::barcode::u64_to_cb_string;
+use carina::barcode::{cb_string_to_u64, u64_to_cb_string};
+
+// the library of an aggregated fragment file is kept in the bits above the
+// 2 bit encoded barcode, 0 for a single library fragment file and for the
+// first library of an aggregated one
+const LIBRARY_SHIFT: usize = 2 * CB_LENGTH;
+
+pub fn cb_with_library(cb: u64, library: u64) -> u64 {
+    (cb & ((1 << LIBRARY_SHIFT) - 1)) | (library << LIBRARY_SHIFT)
+}
+
+pub fn cb_library(cb: u64) -> u64 {
+    cb >> LIBRARY_SHIFT
+}
+
+// barcode w/ the Cell Ranger like 1-based -N library suffix, the default
+// library is written w/o the suffix
+pub fn cb_to_string(cb: u64) -> Result<String, Box<dyn Error>> {
+    let cb_str = u64_to_cb_string(cb & ((1 << LIBRARY_SHIFT) - 1), CB_LENGTH)?;
+    match cb_library(cb) {
+        0 => Ok(cb_str),
+        library => Ok(format!("{}-{}", cb_str, library + 1)),
+    }
+}
+
+// both the bare barcode and the Cell Ranger -1 suffix are the default library
+pub fn cb_from_string(cb_str: &str) -> Result<u64, Box<dyn Error>> {
+    let mut toks = cb_str.splitn(2, '-');
+    let cb = cb_string_to_u64(toks.next().unwrap().as_bytes())?;
+    match toks.next() {
+        Some(library) => match library.parse::<u64>()? {
+            0 => Err(format!("library suffixes start at 1: {}", cb_str).into()),
+            library => Ok(cb_with_library(cb, library - 1)),
+        },
+        None => Ok(cb),
+    }
+}
+
+// FNV-1a hasher seeded w/ a fixed key, unlike the std DefaultHasher its
+// output doesn't change across toolchains or platforms. The state is mixed
+// w/ the splitmix64 finalizer so the high bits are as uniform as the low ones.
+pub struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub fn new(seed: u64) -> StableHasher {
+        let mut hasher = StableHasher {
+            state: StableHasher::OFFSET_BASIS,
+        };
+        hasher.write_u64(seed);
+        hasher
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        let mut x = self.state;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(StableHasher::PRIME);
+        }
+    }
+
+    // fixed byte order and width, the defaults use the native ones
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct Feature {
@@ -60,6 +147,28 @@ impl Fragment {
         }
     }
 
+    // single Tn5 insertion of an orphan or single-end read, stored as a
+    // 1bp fragment at the cut site
+    pub fn new_insertion(aln: &Record, cb: u64) -> Fragment {
+        // same cut site as the end - 1 of a fragment w/ this read as mate
+        let cut_site = match aln.is_reverse() {
+            true => soft_clip_pos(aln) - TN5_RIGHT_OFFSET - 1,
+            false => soft_clip_pos(aln) + TN5_LEFT_OFFSET,
+        };
+        let start = std::cmp::max(0, cut_site);
+
+        Fragment {
+            chr: aln.tid() as u32,
+            start: start as u64,
+            end: start as u64 + 1,
+            cb,
+        }
+    }
+
+    pub fn is_insertion(&self) -> bool {
+        self.end == self.start + 1
+    }
+
     pub fn write(
         &self,
         mut file: &mut BufWriter<File>,
@@ -77,7 +186,7 @@ impl Fragment {
                 self.chr,
                 self.start,
                 self.end,
-                u64_to_cb_string(self.cb, CB_LENGTH)?
+                cb_to_string(self.cb)?
             )?,
             "binary" => {
                 let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -107,7 +216,7 @@ impl Fragment {
                 name,
                 self.start,
                 self.end,
-                u64_to_cb_string(self.cb, CB_LENGTH)?
+                cb_to_string(self.cb)?
             )?,
             "binary" => {
                 let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -127,6 +236,19 @@ impl Fragment {
         bincode::deserialize(&mem_block[..])
     }
 
+    // seeded hash of (chr, start, end, cb), the same across runs
+    pub fn stable_hash(&self, seed: u64) -> u64 {
+        let mut hasher = StableHasher::new(seed);
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // pseudo random number in [0, 1) fixed by the fragment and the seed,
+    // used to keep the same fragments when down-sampling again
+    pub fn hash_fraction(&self, seed: u64) -> f64 {
+        (self.stable_hash(seed) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
     pub fn start(&self) -> u64 {
         self.start
     }
@@ -144,6 +266,10 @@ impl FragmentFile {
             mem_block: [0; 28],
         }
     }
+
+    pub fn from_path(path: &Path) -> Result<FragmentFile, Box<dyn Error>> {
+        Ok(FragmentFile::new(BufReader::new(File::open(path)?)))
+    }
 }
 
 impl Iterator for FragmentFile {
@@ -170,3 +296,42 @@ pub fn soft_clip_pos(aln: &Record) -> i64 {
         false => aln.pos() - softclip_offset as i64,            // forward strand
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CB: &str = "ACGTACGTTTGCAGGC";
+
+    #[test]
+    fn cb_string_round_trip() {
+        let cb = cb_from_string(CB).unwrap();
+        assert_eq!(cb_library(cb), 0);
+        assert_eq!(cb_to_string(cb).unwrap(), CB);
+
+        let cb = cb_from_string(&format!("{}-3", CB)).unwrap();
+        assert_eq!(cb_library(cb), 2);
+        assert_eq!(cb_to_string(cb).unwrap(), format!("{}-3", CB));
+    }
+
+    #[test]
+    fn cb_string_default_library_suffix() {
+        let cb = cb_from_string(&format!("{}-1", CB)).unwrap();
+        assert_eq!(cb, cb_from_string(CB).unwrap());
+        assert_eq!(cb_to_string(cb).unwrap(), CB);
+    }
+
+    #[test]
+    fn cb_string_bad_suffix() {
+        assert!(cb_from_string(&format!("{}-0", CB)).is_err());
+        assert!(cb_from_string(&format!("{}-x", CB)).is_err());
+    }
+
+    #[test]
+    fn cb_with_library_keeps_the_barcode() {
+        let cb = cb_from_string(CB).unwrap();
+        let lib_cb = cb_with_library(cb, 5);
+        assert_eq!(cb_library(lib_cb), 5);
+        assert_eq!(cb_with_library(lib_cb, 0), cb);
+    }
+}
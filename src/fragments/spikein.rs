@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::configs::SPIKEIN_SCALE;
+use crate::fragments::schema::{cb_from_string, cb_to_string};
+
+// name of the row with the counts summed over all the barcodes
+const SAMPLE_ROW: &str = "sample";
+
+// Read groups aligned to the spike-in (E. coli / Drosophila) contigs per
+// barcode, the scaling factor is SPIKEIN_SCALE / spike-in reads.
+#[derive(Default)]
+pub struct SpikeInCounts {
+    cb_counts: HashMap<u64, usize>,
+    total: usize,
+}
+
+pub fn scale_factor(count: usize) -> Option<f32> {
+    match count {
+        0 => None,
+        _ => Some(SPIKEIN_SCALE / count as f32),
+    }
+}
+
+impl SpikeInCounts {
+    pub fn add(&mut self, cb: u64) {
+        *self.cb_counts.entry(cb).or_insert(0) += 1;
+        self.total += 1;
+    }
+
+    // spike-in read w/o a known barcode
+    pub fn add_unknown(&mut self) {
+        self.total += 1;
+    }
+
+    pub fn merge(&mut self, other: &SpikeInCounts) {
+        for (cb, count) in &other.cb_counts {
+            *self.cb_counts.entry(*cb).or_insert(0) += count;
+        }
+        self.total += other.total;
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn cb_count(&self, cb: u64) -> usize {
+        self.cb_counts.get(&cb).copied().unwrap_or(0)
+    }
+
+    pub fn sample_scale(&self) -> Option<f32> {
+        scale_factor(self.total)
+    }
+
+    pub fn cb_scale(&self, cb: u64) -> Option<f32> {
+        scale_factor(self.cb_count(cb))
+    }
+
+    // TSV w/ barcode, spike-in reads and the scaling factor, the first
+    // row has the sample wide numbers
+    pub fn write(&self, out_path: &Path) -> Result<(), Box<dyn Error>> {
+        info!("Creating spike-in counts file: {:?}", out_path);
+        let mut file = BufWriter::new(File::create(out_path)?);
+
+        let to_str = |x: Option<f32>| match x {
+            Some(x) => format!("{:.06}", x),
+            None => "NA".to_string(),
+        };
+        writeln!(file, "barcode\tspikein_reads\tscale_factor")?;
+        writeln!(
+            file,
+            "{}\t{}\t{}",
+            SAMPLE_ROW,
+            self.total,
+            to_str(self.sample_scale())
+        )?;
+
+        let mut cbs: Vec<&u64> = self.cb_counts.keys().collect();
+        cbs.sort();
+        for cb in cbs {
+            writeln!(
+                file,
+                "{}\t{}\t{}",
+                cb_to_string(*cb)?,
+                self.cb_counts[cb],
+                to_str(self.cb_scale(*cb))
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn from_file(in_path: &Path) -> Result<SpikeInCounts, Box<dyn Error>> {
+        let file_path = in_path
+            .canonicalize()
+            .expect("can't find absolute path of input spike-in file");
+        info!("Found spike-in counts file: {:?}", file_path);
+
+        let mut counts = SpikeInCounts::default();
+        for (line_num, line) in BufReader::new(File::open(file_path)?)
+            .lines()
+            .enumerate()
+            .skip(1)
+        {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.split('\t').collect();
+            if toks.len() < 2 {
+                return Err(format!(
+                    "spike-in counts file needs barcode and count columns, line {}: {}",
+                    line_num + 1,
+                    line
+                )
+                .into());
+            }
+            let count: usize = toks[1].trim().parse()?;
+            match toks[0] {
+                SAMPLE_ROW => counts.total = count,
+                cb_str => {
+                    counts.cb_counts.insert(cb_from_string(cb_str)?, count);
+                }
+            };
+        }
+
+        Ok(counts)
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+
+use crate::fragments::schema::{Fragment, FragmentFile};
+
+// Fragments are ordered by their seeded hash and then by the fragment
+// itself, so only duplicate fragment records tie. Keeping every fragment at
+// or below the key of the num_keep-th one keeps exactly num_keep of them,
+// unless that one has duplicates past num_keep, which are kept too.
+type SampleKey = (u64, u32, u64, u64, u64);
+
+fn sample_key(frag: &Fragment, seed: u64) -> SampleKey {
+    (
+        frag.stable_hash(seed),
+        frag.chr,
+        frag.start,
+        frag.end,
+        frag.cb,
+    )
+}
+
+// number of top hash bits used to bucket the fragments for the global target
+const BUCKET_BITS: u32 = 16;
+
+// key at or below which num_keep of the keys are, num_keep is at least 1
+fn key_threshold(mut keys: Vec<SampleKey>, num_keep: usize) -> SampleKey {
+    match num_keep >= keys.len() {
+        true => (u64::MAX, u32::MAX, u64::MAX, u64::MAX, u64::MAX),
+        false => *keys.select_nth_unstable(num_keep - 1).1,
+    }
+}
+
+// The global target is found w/o keeping every key in memory: the first pass
+// counts the fragments per top bits of the hash, the second one only keeps
+// the keys of the bucket where the num_keep-th fragment falls.
+fn global_threshold(path: &Path, seed: u64, num_keep: usize) -> Result<SampleKey, Box<dyn Error>> {
+    let mut bucket_counts = vec![0; 1 << BUCKET_BITS];
+    for frag in FragmentFile::from_path(path)? {
+        bucket_counts[(frag.stable_hash(seed) >> (64 - BUCKET_BITS)) as usize] += 1;
+    }
+
+    let num_frags: usize = bucket_counts.iter().sum();
+    info!(
+        "Keeping {} out of {} fragments",
+        std::cmp::min(num_keep, num_frags).to_formatted_string(&Locale::en),
+        num_frags.to_formatted_string(&Locale::en)
+    );
+    if num_keep >= num_frags {
+        return Ok(key_threshold(Vec::new(), num_keep));
+    }
+
+    let mut num_below = 0;
+    let mut bucket = 0;
+    while num_below + bucket_counts[bucket] < num_keep {
+        num_below += bucket_counts[bucket];
+        bucket += 1;
+    }
+
+    let mut keys = Vec::with_capacity(bucket_counts[bucket]);
+    for frag in FragmentFile::from_path(path)? {
+        let key = sample_key(&frag, seed);
+        if (key.0 >> (64 - BUCKET_BITS)) as usize == bucket {
+            keys.push(key);
+        }
+    }
+
+    Ok(key_threshold(keys, num_keep - num_below))
+}
+
+// Fragments are kept if the seeded hash of (chr, start, end, cb) is under
+// a threshold, either global or per barcode, so reruns keep the same ones.
+enum Threshold {
+    Fraction(f64),
+    Global(SampleKey),
+    PerCell(HashMap<u64, SampleKey>),
+}
+
+impl Threshold {
+    fn from_clap(sub_m: &ArgMatches, path: &Path, seed: u64) -> Result<Threshold, Box<dyn Error>> {
+        if let Some(fraction) = sub_m.value_of("fraction") {
+            let fraction: f64 = fraction.parse().expect("can't parse fraction");
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(format!("fraction has to be in (0, 1]: {}", fraction).into());
+            }
+            info!("Keeping {:.02}% of the fragments", fraction * 100.0);
+            return Ok(Threshold::Fraction(fraction));
+        }
+
+        if let Some(target) = sub_m.value_of("target") {
+            let target: usize = target.parse().expect("can't parse target fragments");
+            if target == 0 {
+                return Err("target fragments has to be positive".into());
+            }
+            return Ok(Threshold::Global(global_threshold(path, seed, target)?));
+        }
+
+        let max_frags: usize = sub_m
+            .value_of("maxpercell")
+            .expect("can't find subsampling method")
+            .parse()
+            .expect("can't parse max fragments per cell");
+        if max_frags == 0 {
+            return Err("max fragments per cell has to be positive".into());
+        }
+
+        let mut cb_frags: HashMap<u64, usize> = HashMap::new();
+        for frag in FragmentFile::from_path(path)? {
+            *cb_frags.entry(frag.cb).or_insert(0) += 1;
+        }
+
+        // the keys are collected only for the barcodes over the cap
+        let mut cb_keys: HashMap<u64, Vec<SampleKey>> = cb_frags
+            .iter()
+            .filter(|(_, count)| **count > max_frags)
+            .map(|(cb, count)| (*cb, Vec::with_capacity(*count)))
+            .collect();
+        info!(
+            "Capping {} out of {} barcodes at {} fragments",
+            cb_keys.len().to_formatted_string(&Locale::en),
+            cb_frags.len().to_formatted_string(&Locale::en),
+            max_frags.to_formatted_string(&Locale::en)
+        );
+        for frag in FragmentFile::from_path(path)? {
+            if let Some(keys) = cb_keys.get_mut(&frag.cb) {
+                keys.push(sample_key(&frag, seed));
+            }
+        }
+
+        Ok(Threshold::PerCell(
+            cb_keys
+                .into_iter()
+                .map(|(cb, keys)| (cb, key_threshold(keys, max_frags)))
+                .collect(),
+        ))
+    }
+
+    fn keep(&self, frag: &Fragment, seed: u64) -> bool {
+        match self {
+            Threshold::Fraction(fraction) => frag.hash_fraction(seed) < *fraction,
+            Threshold::Global(threshold) => sample_key(frag, seed) <= *threshold,
+            Threshold::PerCell(cb_thresholds) => match cb_thresholds.get(&frag.cb) {
+                Some(threshold) => sample_key(frag, seed) <= *threshold,
+                None => true,
+            },
+        }
+    }
+}
+
+pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let ibed_file_path = carina::file::file_path_from_clap(sub_m, "ibed")?;
+    let seed: u64 = sub_m
+        .value_of("seed")
+        .unwrap_or("0")
+        .parse()
+        .expect("can't parse seed");
+    let threshold = Threshold::from_clap(sub_m, &ibed_file_path, seed)?;
+
+    let mut obed_file = carina::file::bufwriter_from_clap(sub_m, "obed")?;
+    let mut num_frags = 0;
+    let mut num_kept = 0;
+    for frag in FragmentFile::from_path(&ibed_file_path)? {
+        num_frags += 1;
+        if num_frags % crate::configs::MIL == 0 {
+            print!(
+                "\rDone processing {}M fragments",
+                num_frags / crate::configs::MIL
+            );
+            std::io::stdout().flush().expect("Can't flush output");
+        }
+
+        if threshold.keep(&frag, seed) {
+            frag.write(&mut obed_file, "binary")?;
+            num_kept += 1;
+        }
+    }
+    println!();
+
+    info!(
+        "Kept {} out of {} fragments",
+        num_kept.to_formatted_string(&Locale::en),
+        num_frags.to_formatted_string(&Locale::en)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::PathBuf;
+
+    fn fragments(num_frags: u64) -> Vec<Fragment> {
+        (0..num_frags)
+            .map(|idx| Fragment {
+                chr: (idx % 3) as u32,
+                start: idx * 100,
+                end: idx * 100 + 150,
+                cb: idx % 7,
+            })
+            .collect()
+    }
+
+    fn write_fragments(frags: &[Fragment], name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("volans.{}.{}.frags", name, std::process::id()));
+        let mut file = BufWriter::new(File::create(&path).unwrap());
+        for frag in frags {
+            frag.write(&mut file, "binary").unwrap();
+        }
+        file.flush().unwrap();
+        path
+    }
+
+    #[test]
+    fn key_threshold_keeps_num_keep() {
+        let keys: Vec<SampleKey> = fragments(50).iter().map(|x| sample_key(x, 3)).collect();
+        let threshold = key_threshold(keys.clone(), 20);
+        assert_eq!(keys.iter().filter(|x| **x <= threshold).count(), 20);
+
+        let threshold = key_threshold(keys.clone(), 1);
+        assert_eq!(threshold, *keys.iter().min().unwrap());
+    }
+
+    #[test]
+    fn key_threshold_keeps_all_under_the_target() {
+        let keys: Vec<SampleKey> = fragments(10).iter().map(|x| sample_key(x, 3)).collect();
+        let threshold = key_threshold(keys.clone(), 10);
+        assert!(keys.iter().all(|x| *x <= threshold));
+    }
+
+    #[test]
+    fn global_threshold_matches_the_in_memory_one() {
+        let frags = fragments(2_000);
+        let path = write_fragments(&frags, "subsample");
+        let keys: Vec<SampleKey> = frags.iter().map(|x| sample_key(x, 11)).collect();
+
+        for num_keep in &[1, 150, 1_999] {
+            let threshold = global_threshold(&path, 11, *num_keep).unwrap();
+            assert_eq!(threshold, key_threshold(keys.clone(), *num_keep));
+            assert_eq!(keys.iter().filter(|x| **x <= threshold).count(), *num_keep);
+        }
+
+        let threshold = global_threshold(&path, 11, 5_000).unwrap();
+        assert!(keys.iter().all(|x| *x <= threshold));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,17 +1,24 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::error::Error;
-use std::io::Write;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use clap::ArgMatches;
 
 use rust_htslib::bam;
+use rust_htslib::bam::header::HeaderRecord;
+use rust_htslib::bam::record::Aux;
 use rust_htslib::bam::{Read, Record};
 
 use itertools::Itertools;
 
 use carina::barcode::cb_string_to_u64;
 
-use crate::fragments::count_stats;
-use crate::fragments::filter;
+use crate::fragments::filter::{
+    ContigFilter, FilterChain, FilterConfig, FragmentAlignments, RejectReason, SpikeInFilter,
+};
 use crate::fragments::schema::Fragment;
 
 pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
@@ -39,20 +46,27 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
         _ => true,
     };
 
-    let mito_string = sub_m
-        .value_of("mitostr")
-        .expect("can't find string to identify mitochondrial chromosome");
-    let mito_tid = bam_header
-        .tid(mito_string.as_bytes())
-        .expect("Can't find mito string in the BAM file");
-    info!(
-        "Using {} as Mitochondrial Chromosome with {} as id.",
-        mito_string, mito_tid
-    );
-
-    let mut counter = count_stats::FragStats {
-        ..Default::default()
+    // the mitochondrial chromosome is always excluded in addition to
+    // the --exclude list
+    let contigs = ContigFilter::from_clap(sub_m, &bam_header, sub_m.value_of("mitostr"))?;
+    let spikein = SpikeInFilter::from_clap(sub_m, &bam_header)?;
+    let filters = FilterChain::from_config(FilterConfig {
+        is_tenx,
+        spikein,
+        contigs,
+        ..FilterConfig::from_clap(sub_m)
+    });
+    info!("Using filters: {}", filters.names().join(", "));
+
+    let mut rejects = match sub_m.value_of("rejects") {
+        Some(path) => Some(RejectsWriter::new(
+            Path::new(path),
+            bam::Header::from_template(&bam_header),
+        )?),
+        None => None,
     };
+
+    let mut counter = filters.new_stats();
     for (_, read_group) in input_bam
         .records()
         .map(|res| res.unwrap())
@@ -68,16 +82,261 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
             std::io::stdout().flush().expect("Can't flush output");
         }
 
-        let alignments: Vec<Record> = read_group.collect();
-        match filter::callback(&alignments, &mut counter, just_stats, is_tenx, mito_tid) {
-            Some((aln, maln)) => {
-                let frag = Fragment::new(aln, maln, cb_extractor);
+        let mut alignments: Vec<Record> = read_group.collect();
+        let dropped = filters.prepare(&mut alignments);
+        if let Some(rejects_writer) = &mut rejects {
+            for (reason, records) in dropped {
+                rejects_writer.write(&records, reason)?;
+            }
+        }
+        match filters.apply(&alignments, &mut counter) {
+            Ok(frag_alns) => {
+                if just_stats {
+                    continue;
+                }
+
+                let frag = match frag_alns {
+                    FragmentAlignments::Pair(aln, maln) => Fragment::new(aln, maln, cb_extractor),
+                    FragmentAlignments::Insertion(aln) => {
+                        Fragment::new_insertion(aln, cb_extractor(aln))
+                    }
+                };
                 frag.write(&mut obed_file, "binary")?;
             }
-            None => continue,
+            Err(reason) => {
+                // spike-in reads w/o a barcode are only in the sample total
+                if reason == RejectReason::SpikeIn {
+                    let first_aln = alignments.first().unwrap();
+                    match !is_tenx || first_aln.aux(b"CB").is_some() {
+                        true => counter.spikein.add(cb_extractor(first_aln)),
+                        false => counter.spikein.add_unknown(),
+                    };
+                }
+                if let Some(rejects_writer) = &mut rejects {
+                    rejects_writer.write(&alignments, reason)?;
+                }
+            }
         };
     }
 
+    if let Some(rejects_writer) = rejects {
+        rejects_writer.finish()?;
+    }
+    if let Some(path) = sub_m.value_of("ospikein") {
+        counter.spikein.write(Path::new(path))?;
+    }
+
     println!("{}", counter);
     Ok(())
 }
+
+// sorting key of the alignment, unmapped records w/ tid -1 go last
+fn coordinate_key(aln: &Record) -> (u32, i64) {
+    (aln.tid() as u32, aln.pos())
+}
+
+// copy of the header w/ the @HD line marking the records as coordinate
+// sorted, every other line is kept as is
+fn coordinate_sorted_header(header: &bam::Header) -> Result<bam::Header, Box<dyn Error>> {
+    let mut sorted_header = bam::Header::new();
+    let mut hd_record = HeaderRecord::new(b"HD");
+    hd_record.push_tag(b"VN", &"1.6");
+    hd_record.push_tag(b"SO", &"coordinate");
+    sorted_header.push_record(&hd_record);
+
+    let text = String::from_utf8(header.to_bytes())?;
+    for line in text.lines().filter(|x| !x.is_empty()) {
+        let mut toks = line.split('\t');
+        let tag = toks.next().unwrap().trim_start_matches('@');
+        match tag {
+            "HD" => continue,
+            "CO" => {
+                sorted_header.push_comment(line.trim_start_matches("@CO\t").as_bytes());
+            }
+            _ => {
+                let mut record = HeaderRecord::new(tag.as_bytes());
+                for tok in toks {
+                    match tok.find(':') {
+                        Some(idx) => record.push_tag(tok[..idx].as_bytes(), &&tok[idx + 1..]),
+                        None => return Err(format!("malformed BAM header line: {}", line).into()),
+                    };
+                }
+                sorted_header.push_record(&record);
+            }
+        };
+    }
+
+    Ok(sorted_header)
+}
+
+// BAM output which either streams the records as they come or, when sorted,
+// spills coordinate sorted chunks to disk and k-way merges them on finish
+pub struct BamWriter {
+    out_path: PathBuf,
+    header: bam::Header,
+    writer: Option<bam::Writer>,
+    buffer: Vec<Record>,
+    chunk_paths: Vec<PathBuf>,
+}
+
+impl BamWriter {
+    pub fn new(
+        out_path: &Path,
+        header: bam::Header,
+        is_sorted: bool,
+    ) -> Result<BamWriter, Box<dyn Error>> {
+        info!("Creating output BAM file: {:?}", out_path);
+        let (header, writer) = match is_sorted {
+            true => (coordinate_sorted_header(&header)?, None),
+            false => {
+                let writer = bam::Writer::from_path(out_path, &header, bam::Format::BAM)?;
+                (header, Some(writer))
+            }
+        };
+
+        Ok(BamWriter {
+            out_path: out_path.to_path_buf(),
+            header,
+            writer,
+            buffer: Vec::new(),
+            chunk_paths: Vec::new(),
+        })
+    }
+
+    pub fn write(&mut self, record: Record) -> Result<(), Box<dyn Error>> {
+        match &mut self.writer {
+            Some(writer) => writer.write(&record)?,
+            None => {
+                self.buffer.push(record);
+                if self.buffer.len() >= crate::configs::SORT_BUFFER_SIZE {
+                    self.spill()?;
+                }
+            }
+        };
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), Box<dyn Error>> {
+        let chunk_path = PathBuf::from(format!(
+            "{}.chunk{}",
+            self.out_path.to_str().unwrap(),
+            self.chunk_paths.len()
+        ));
+
+        self.buffer
+            .sort_by(|a, b| coordinate_key(a).cmp(&coordinate_key(b)));
+        let mut writer = bam::Writer::from_path(&chunk_path, &self.header, bam::Format::BAM)?;
+        for record in self.buffer.drain(..) {
+            writer.write(&record)?;
+        }
+
+        self.chunk_paths.push(chunk_path);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        if self.writer.is_some() {
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        info!("Merging {} sorted BAM chunks", self.chunk_paths.len());
+        {
+            let mut readers = Vec::new();
+            for chunk_path in &self.chunk_paths {
+                readers.push(bam::Reader::from_path(chunk_path)?);
+            }
+            let mut chunk_iters: Vec<_> = readers.iter_mut().map(|x| x.records()).collect();
+
+            let mut heads: Vec<Option<Record>> = Vec::new();
+            let mut heap = BinaryHeap::new();
+            for (chunk_idx, chunk_iter) in chunk_iters.iter_mut().enumerate() {
+                let head = chunk_iter.next().transpose()?;
+                if let Some(record) = &head {
+                    heap.push(Reverse((coordinate_key(record), chunk_idx)));
+                }
+                heads.push(head);
+            }
+
+            let mut writer =
+                bam::Writer::from_path(&self.out_path, &self.header, bam::Format::BAM)?;
+            while let Some(Reverse((_, chunk_idx))) = heap.pop() {
+                let record = heads[chunk_idx].take().unwrap();
+                writer.write(&record)?;
+
+                heads[chunk_idx] = chunk_iters[chunk_idx].next().transpose()?;
+                if let Some(record) = &heads[chunk_idx] {
+                    heap.push(Reverse((coordinate_key(record), chunk_idx)));
+                }
+            }
+        } // closing all files.
+
+        for chunk_path in &self.chunk_paths {
+            std::fs::remove_file(chunk_path)?;
+        }
+
+        info!("Indexing output BAM file: {:?}", self.out_path);
+        bam::index::build(&self.out_path, None, bam::index::Type::BAI, 1)?;
+        Ok(())
+    }
+}
+
+// rejected read groups w/ the filter reason, either as a BAM w/ the reason
+// in the ZF tag or a TSV if the file name ends w/ .tsv
+pub enum RejectsWriter {
+    Bam(BamWriter),
+    Tsv(BufWriter<File>),
+}
+
+impl RejectsWriter {
+    pub fn new(out_path: &Path, header: bam::Header) -> Result<RejectsWriter, Box<dyn Error>> {
+        match out_path.extension().and_then(|x| x.to_str()) {
+            Some("tsv") => {
+                info!("Creating rejected reads TSV file: {:?}", out_path);
+                let mut file = BufWriter::new(File::create(out_path)?);
+                writeln!(file, "qname\treason\tflag\ttid\tpos\tmapq")?;
+                Ok(RejectsWriter::Tsv(file))
+            }
+            _ => Ok(RejectsWriter::Bam(BamWriter::new(out_path, header, false)?)),
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        alignments: &[Record],
+        reason: RejectReason,
+    ) -> Result<(), Box<dyn Error>> {
+        for aln in alignments {
+            match self {
+                RejectsWriter::Bam(writer) => {
+                    let mut aln = aln.clone();
+                    aln.push_aux(b"ZF", &Aux::String(reason.as_str().as_bytes()));
+                    writer.write(aln)?;
+                }
+                RejectsWriter::Tsv(file) => writeln!(
+                    file,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    std::str::from_utf8(aln.qname())?,
+                    reason.as_str(),
+                    aln.flags(),
+                    aln.tid(),
+                    aln.pos(),
+                    aln.mapq()
+                )?,
+            };
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            RejectsWriter::Bam(writer) => writer.finish(),
+            RejectsWriter::Tsv(mut file) => Ok(file.flush()?),
+        }
+    }
+}
@@ -1,63 +1,292 @@
 use std::error::Error;
 use std::fs::File;
 use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
 
 use crate::carina::fastq::FastqFeeder3;
+use crate::fragments::filter::{
+    ContigFilter, DroppedRecords, FilterChain, FilterConfig, FragmentAlignments, RejectReason,
+    SpikeInFilter,
+};
 use crate::fragments::schema::Fragment;
-use bwa::BwaAligner;
+use crate::io::bam::{BamWriter, RejectsWriter};
+use bio::io::fastq;
+use bwa::{BwaAligner, BwaReference, BwaSettings, PairedEndStats};
 use carina::barcode::cb_string_to_u64;
 use clap::ArgMatches;
+use rayon::prelude::*;
+use rust_htslib::bam::header::HeaderRecord;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{HeaderView, Record};
 
 use crate::fragments::count_stats;
-use crate::fragments::filter;
+
+pub struct ReadGroup {
+    pub id: String,
+    pub sample: String,
+}
+
+impl ReadGroup {
+    pub fn from_clap(sub_m: &ArgMatches) -> ReadGroup {
+        let sample = sub_m.value_of("rgsm").unwrap_or("volans").to_string();
+        let id = match sub_m.value_of("rgid") {
+            Some(id) => id.to_string(),
+            None => sample.clone(),
+        };
+
+        ReadGroup { id, sample }
+    }
+
+    // @RG line for the SAM/BAM header
+    pub fn header_line(&self) -> String {
+        format!("@RG\tID:{}\tSM:{}\tPL:ILLUMINA", self.id, self.sample)
+    }
+
+    // header record built from the @RG line, so the two always agree
+    pub fn header_record(&self) -> HeaderRecord {
+        let line = self.header_line();
+        let mut toks = line.split('\t');
+        let tag = toks.next().unwrap().trim_start_matches('@');
+
+        let mut record = HeaderRecord::new(tag.as_bytes());
+        for tok in toks {
+            let mut key_val = tok.splitn(2, ':');
+            let key = key_val.next().unwrap();
+            record.push_tag(key.as_bytes(), &key_val.next().unwrap_or(""));
+        }
+        record
+    }
+}
+
+fn bwa_from_clap(sub_m: &ArgMatches) -> Result<BwaAligner, Box<dyn Error>> {
+    let index_path = carina::file::file_path_from_clap(sub_m, "index")?;
+    let reference = BwaReference::open(index_path)?;
+
+    let mut settings = BwaSettings::new();
+    if let Some(seed_len) = sub_m.value_of("seedlen") {
+        settings = settings.set_seed_len(seed_len.parse().expect("can't parse seed length"));
+    }
+    if let Some(clip) = sub_m.value_of("clip") {
+        let clip_penalty: i32 = clip.parse().expect("can't parse clipping penalty");
+        settings = settings.set_clip_scores(clip_penalty, clip_penalty);
+    }
+
+    // insert size model as mean,std,min,max of the fragment length
+    let pe_stats = match sub_m.value_of("isize") {
+        Some(isize_str) => {
+            let bad_isize = || format!("insert size model needs mean,std,min,max: {}", isize_str);
+            let toks: Vec<&str> = isize_str.split(',').map(|x| x.trim()).collect();
+            if toks.len() != 4 {
+                return Err(bad_isize().into());
+            }
+            PairedEndStats::simple(
+                toks[0].parse().map_err(|_| bad_isize())?,
+                toks[1].parse().map_err(|_| bad_isize())?,
+                toks[2].parse().map_err(|_| bad_isize())?,
+                toks[3].parse().map_err(|_| bad_isize())?,
+            )
+        }
+        None => PairedEndStats::default(),
+    };
+
+    Ok(BwaAligner::new(reference, settings, pe_stats))
+}
+
+type ReadTriplet = (fastq::Record, fastq::Record, fastq::Record);
+type AlignedPair = (
+    Result<(Fragment, Vec<Record>), (RejectReason, Vec<Record>)>,
+    DroppedRecords,
+);
+
+// aligns the read pair and filters the alignments, the records are returned
+// only when they are written to the output or the rejects BAM, along w/ the
+// records dropped before filtering
+fn align_read_pair(
+    bwa: &BwaAligner,
+    record: &ReadTriplet,
+    read_group: &ReadGroup,
+    filters: &FilterChain,
+    keep_alns: bool,
+    keep_rejects: bool,
+    mut counter: &mut count_stats::FragStats,
+) -> AlignedPair {
+    counter.total_reads += 1;
+
+    let read_name = record.0.id().split_whitespace().next().unwrap_or("");
+    let (mut r1_alns, mut r2_alns) = bwa.align_read_pair(
+        read_name.as_bytes(),
+        record.0.seq(),
+        record.0.qual(),
+        record.2.seq(),
+        record.2.qual(),
+    );
+    assert!(
+        r1_alns.len() == r2_alns.len(),
+        "uneven number of alignments"
+    );
+
+    r1_alns.append(&mut r2_alns);
+    for aln in r1_alns.iter_mut() {
+        aln.push_aux(b"RG", &Aux::String(read_group.id.as_bytes()));
+    }
+
+    let cb_name = record.1.seq();
+    let cb_name = &cb_name[(cb_name.len() - crate::configs::CB_LENGTH)..];
+    let cb = cb_string_to_u64(cb_name).expect("can't convert cb string to u64");
+
+    let mut dropped = filters.prepare(&mut r1_alns);
+    if !keep_rejects {
+        dropped.clear();
+    }
+    let frag_alns = match filters.apply(&r1_alns, &mut counter) {
+        Ok(frag) => frag,
+        Err(reason) => {
+            if reason == RejectReason::SpikeIn {
+                counter.spikein.add(cb);
+            }
+            return match keep_rejects {
+                true => (Err((reason, r1_alns)), dropped),
+                false => (Err((reason, Vec::new())), dropped),
+            };
+        }
+    };
+    let frag = match frag_alns {
+        FragmentAlignments::Pair(aln, maln) => Fragment::new_with_cb(aln, maln, cb),
+        FragmentAlignments::Insertion(aln) => Fragment::new_insertion(aln, cb),
+    };
+
+    let mut alns = Vec::new();
+    if keep_alns {
+        for aln in frag_alns.records() {
+            let mut aln = aln.clone();
+            aln.push_aux(b"CB", &Aux::String(cb_name));
+            alns.push(aln);
+        }
+    }
+
+    (Ok((frag, alns)), dropped)
+}
+
+// output files of the aligned reads, the fragments are always written
+pub struct AlignOutput {
+    pub obed_file: std::io::BufWriter<std::fs::File>,
+    pub obam: Option<BamWriter>,
+    pub rejects: Option<RejectsWriter>,
+}
 
 pub fn process_reads(
     fq_feeder: FastqFeeder3<File>,
     bwa: BwaAligner,
-    mut obed_file: std::io::BufWriter<std::fs::File>,
-) -> Result<(), Box<dyn Error>> {
-    let mut counter = count_stats::FragStats {
-        ..Default::default()
-    };
+    read_group: &ReadGroup,
+    filters: &FilterChain,
+    num_threads: usize,
+    output: AlignOutput,
+) -> Result<count_stats::FragStats, Box<dyn Error>> {
+    let AlignOutput {
+        mut obed_file,
+        mut obam,
+        mut rejects,
+    } = output;
+    let keep_alns = obam.is_some();
+    let keep_rejects = rejects.is_some();
+    let mut counter = filters.new_stats();
 
-    for record in fq_feeder {
-        counter.total_reads += 1;
-        if counter.total_reads % crate::configs::TKILO == 0 {
-            print!(
-                "\rDone processing {}0 K reads",
-                counter.total_reads / crate::configs::TKILO
-            );
-            std::io::stdout().flush().expect("Can't flush output");
-        }
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()?;
+    info!("Aligning reads using {} threads", num_threads);
 
-        let (mut r1_alns, mut r2_alns) = bwa.align_read_pair(
-            b"jdoe",
-            record.0.seq(),
-            record.0.qual(),
-            record.2.seq(),
-            record.2.qual(),
-        );
-        assert!(
-            r1_alns.len() == r2_alns.len(),
-            "uneven number of alignments"
-        );
+    // reading the fastq in batches on a separate thread, while the current
+    // batch is being aligned by the workers
+    let (batch_sender, batch_receiver) = mpsc::sync_channel::<Vec<ReadTriplet>>(2);
+    let reader = thread::spawn(move || {
+        let mut fq_feeder = fq_feeder;
+        loop {
+            let batch: Vec<ReadTriplet> = fq_feeder
+                .by_ref()
+                .take(crate::configs::ALN_BATCH_SIZE)
+                .collect();
+            if batch.is_empty() || batch_sender.send(batch).is_err() {
+                break;
+            }
+        }
+    });
 
-        r1_alns.append(&mut r2_alns);
-        match filter::callback(&r1_alns, &mut counter, false, false, 10_000) {
-            Some((aln, maln)) => {
-                let cb_name = record.1.seq();
-                let cb = cb_string_to_u64(&cb_name[(cb_name.len() - crate::configs::CB_LENGTH)..])
-                    .expect("can't convert cb string to u64");
+    for batch in batch_receiver {
+        // fold keeps a FragStats per worker and reduce merges them in order,
+        // so the fragments are written in the same order as the input reads
+        let (frags, batch_counter) = thread_pool.install(|| {
+            batch
+                .par_iter()
+                .fold(
+                    || (Vec::new(), filters.new_stats()),
+                    |(mut frags, mut worker_counter), record| {
+                        frags.push(align_read_pair(
+                            &bwa,
+                            record,
+                            read_group,
+                            filters,
+                            keep_alns,
+                            keep_rejects,
+                            &mut worker_counter,
+                        ));
+                        (frags, worker_counter)
+                    },
+                )
+                .reduce(
+                    || (Vec::new(), filters.new_stats()),
+                    |(mut frags, mut worker_counter), (other_frags, other_counter)| {
+                        frags.extend(other_frags);
+                        worker_counter.merge(&other_counter);
+                        (frags, worker_counter)
+                    },
+                )
+        });
 
-                let frag = Fragment::new_with_cb(aln, maln, cb);
-                frag.write(&mut obed_file, "text")?;
+        for (aligned_pair, dropped) in frags {
+            if let Some(rejects_writer) = &mut rejects {
+                for (reason, records) in dropped {
+                    rejects_writer.write(&records, reason)?;
+                }
             }
-            None => continue,
-        };
+
+            match aligned_pair {
+                Ok((frag, alns)) => {
+                    frag.write(&mut obed_file, "text")?;
+                    if let Some(bam_writer) = &mut obam {
+                        for aln in alns {
+                            bam_writer.write(aln)?;
+                        }
+                    }
+                }
+                Err((reason, alns)) => {
+                    if let Some(rejects_writer) = &mut rejects {
+                        rejects_writer.write(&alns, reason)?;
+                    }
+                }
+            };
+        }
+
+        counter.merge(&batch_counter);
+        print!(
+            "\rDone processing {}0 K reads",
+            counter.total_reads / crate::configs::TKILO
+        );
+        std::io::stdout().flush().expect("Can't flush output");
+    }
+    reader.join().expect("fastq reader thread panicked");
+
+    if let Some(bam_writer) = obam {
+        bam_writer.finish()?;
     }
-    
+    if let Some(rejects_writer) = rejects {
+        rejects_writer.finish()?;
+    }
+
     println!("{}", counter);
-    Ok(())
+    Ok(counter)
 }
 
 pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
@@ -68,11 +297,52 @@ pub fn callback(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let fastq_paths = vec![fastq_one_path, fastq_second_path, fastq_third_path];
     let fq_feeder: FastqFeeder3<File> = FastqFeeder3::<File>::new(fastq_paths);
 
-    let index_path = carina::file::file_path_from_clap(sub_m, "index")?;
-    let bwa = BwaAligner::from_path(index_path)?;
+    let bwa = bwa_from_clap(sub_m)?;
+    let read_group = ReadGroup::from_clap(sub_m);
+    info!("Using read group {}", read_group.header_line());
 
     let obed_file = carina::file::bufwriter_from_clap(sub_m, "obed")?;
 
-    process_reads(fq_feeder, bwa, obed_file)?;
+    let num_threads: usize = sub_m
+        .value_of("threads")
+        .unwrap_or("1")
+        .parse()
+        .expect("can't parse number of threads");
+    let mut header = bwa.create_bam_header();
+    header.push_record(&read_group.header_record());
+
+    // the mitochondrial chromosome is always excluded in addition to
+    // the --exclude list
+    let header_view = HeaderView::from_header(&header);
+    let spikein = SpikeInFilter::from_clap(sub_m, &header_view)?;
+    let contigs = ContigFilter::from_clap(sub_m, &header_view, sub_m.value_of("mitostr"))?;
+    let filters = FilterChain::from_config(FilterConfig {
+        spikein,
+        contigs,
+        ..FilterConfig::from_clap(sub_m)
+    });
+    info!("Using filters: {}", filters.names().join(", "));
+
+    let obam = match sub_m.value_of("obam") {
+        Some(path) => {
+            let is_sorted = sub_m.is_present("sortbam");
+            Some(BamWriter::new(Path::new(path), header.clone(), is_sorted)?)
+        }
+        None => None,
+    };
+    let rejects = match sub_m.value_of("rejects") {
+        Some(path) => Some(RejectsWriter::new(Path::new(path), header)?),
+        None => None,
+    };
+
+    let output = AlignOutput {
+        obed_file,
+        obam,
+        rejects,
+    };
+    let counter = process_reads(fq_feeder, bwa, &read_group, &filters, num_threads, output)?;
+    if let Some(path) = sub_m.value_of("ospikein") {
+        counter.spikein.write(Path::new(path))?;
+    }
     Ok(())
 }
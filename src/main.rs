@@ -2,14 +2,18 @@ extern crate bincode;
 extern crate bio;
 extern crate bitvector;
 extern crate clap;
+extern crate flate2;
+#[cfg(feature = "hdf5")]
+extern crate hdf5;
 extern crate indicatif;
 extern crate itertools;
 extern crate num_format;
 extern crate pretty_env_logger;
 extern crate quickersort;
+extern crate rayon;
+extern crate regex;
 extern crate rust_htslib;
 extern crate serde;
-extern crate sprs;
 
 extern crate bwa;
 extern crate carina;
@@ -17,12 +21,13 @@ extern crate carina;
 #[macro_use]
 extern crate log;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgGroup, SubCommand};
 use std::error::Error;
 
 pub mod configs;
 pub mod fragments;
 pub mod io;
+pub mod preprocess;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("volans")
@@ -68,6 +73,125 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .takes_value(true)
                         .required(true)
                         .help("path to the output bed file"),
+                )
+                .arg(
+                    Arg::with_name("obam")
+                        .long("obam")
+                        .takes_value(true)
+                        .help("path to the optional output BAM file"),
+                )
+                .arg(
+                    Arg::with_name("sortbam")
+                        .long("sortbam")
+                        .requires("obam")
+                        .help("coordinate sort and index the output BAM file."),
+                )
+                .arg(
+                    Arg::with_name("rejects")
+                        .long("rejects")
+                        .takes_value(true)
+                        .help("path to the BAM (or .tsv) file w/ rejected reads and reason."),
+                )
+                .arg(
+                    Arg::with_name("mitostr")
+                        .long("mitostr")
+                        .short("m")
+                        .takes_value(true)
+                        .default_value("chrM")
+                        .help("String to identify the mitochondrial chromosome"),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("contig names or regexes to keep, e.g. chr[0-9XY]+"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("contig names or regexes to skip, e.g. chrM,chrEBV,.*_random"),
+                )
+                .arg(
+                    Arg::with_name("spikein")
+                        .long("spikein")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .requires("ospikein")
+                        .help("spike-in contig names or regexes, e.g. E.coli or dm.*"),
+                )
+                .arg(
+                    Arg::with_name("ospikein")
+                        .long("ospikein")
+                        .takes_value(true)
+                        .requires("spikein")
+                        .help("path to the output TSV w/ per cell spike-in reads and scale."),
+                )
+                .arg(
+                    Arg::with_name("multimap")
+                        .long("multimap")
+                        .takes_value(true)
+                        .possible_values(&["strict", "primary", "mapq", "random", "flag"])
+                        .default_value("strict")
+                        .help("how to handle multimapping reads."),
+                )
+                .arg(
+                    Arg::with_name("mapq")
+                        .long("mapq")
+                        .takes_value(true)
+                        .default_value("30")
+                        .help("minimum MAPQ of both the mates."),
+                )
+                .arg(
+                    Arg::with_name("orphans")
+                        .long("orphans")
+                        .help("keep orphan and single-end reads as single Tn5 insertions."),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .short("t")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("number of threads used for the alignment"),
+                )
+                .arg(
+                    Arg::with_name("seedlen")
+                        .long("seedlen")
+                        .short("k")
+                        .takes_value(true)
+                        .help("minimum seed length"),
+                )
+                .arg(
+                    Arg::with_name("clip")
+                        .long("clip")
+                        .short("L")
+                        .takes_value(true)
+                        .help("penalty for 5'- and 3'-end clipping"),
+                )
+                .arg(
+                    Arg::with_name("isize")
+                        .long("isize")
+                        .takes_value(true)
+                        .help("insert size model as comma separated mean,std,min,max"),
+                )
+                .arg(
+                    Arg::with_name("rgid")
+                        .long("rgid")
+                        .takes_value(true)
+                        .help("read group id, defaults to the sample name"),
+                )
+                .arg(
+                    Arg::with_name("rgsm")
+                        .long("rgsm")
+                        .takes_value(true)
+                        .default_value("volans")
+                        .help("sample name of the read group"),
                 ),
         )
         .subcommand(
@@ -95,168 +219,759 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("path to the input fastq file w/ barcodes"),
                 ),
         )
-        //.subcommand(
-        //    SubCommand::with_name("filter")
-        //        .about("A subcommand to filter BAM and generate BED.")
-        //        .arg(
-        //            Arg::with_name("ibam")
-        //                .long("ibam")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BAM file"),
-        //        )
-        //        .arg(
-        //            Arg::with_name("obed")
-        //                .long("obed")
-        //                .short("o")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the output bed file"),
-        //        )
-        //        .arg(
-        //            Arg::with_name("tenx")
-        //                .long("tenx")
-        //                .help("use tag CB from 10x generated BAM."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("stats")
-        //                .long("stats")
-        //                .help("Don't write the output BED, just produce stats."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("mitostr")
-        //                .long("mitostr")
-        //                .short("m")
-        //                .takes_value(true)
-        //                .default_value("chrM")
-        //                .help("String to identify the mitochondrial chromosome"),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("correct")
-        //        .about("A subcommand to sequence correct the cb sequences.")
-        //        .arg(
-        //            Arg::with_name("whitelist")
-        //                .long("whitelist")
-        //                .short("w")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the list of known whitelist CB."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file with CB sequences."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("sort")
-        //        .about("A subcommand to sort the file by a chromosome names.")
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file with CB sequences."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("group")
-        //        .about("A subcommand to group the file by (chr, start, end, CB)")
-        //        .arg(
-        //            Arg::with_name("allcb")
-        //                .long("allcb")
-        //                .help("report all cb instead of count."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file with CB sequences."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("callpeak")
-        //        .about("A subcommand to call peaks from a grouped bed file")
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the grouped BED file."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("count")
-        //        .about("A subcommand to generate peak v cell count matrix")
-        //        .arg(
-        //            Arg::with_name("bam")
-        //                .long("bam")
-        //                .short("b")
-        //                .takes_value(true)
-        //                .help("path to the bam file with the chromosome names."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("pbed")
-        //                .long("pbed")
-        //                .short("p")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file with the annotated peaks."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("cbed")
-        //                .long("cbed")
-        //                .short("c")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file fragments and CB."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("text")
-        //        .about("A subcommand to convert binary bed to text.")
-        //        .arg(
-        //            Arg::with_name("cbtext")
-        //                .long("cbtext")
-        //                .help("writes the last column as CB sequence."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("bam")
-        //                .long("bam")
-        //                .short("b")
-        //                .takes_value(true)
-        //                .help("path to the bam file with the chromosome names."),
-        //        )
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file with CB sequences."),
-        //        ),
-        //)
-        //.subcommand(
-        //    SubCommand::with_name("stats")
-        //        .about("A subcommand to summary stats of the binary bed.")
-        //        .arg(
-        //            Arg::with_name("ibed")
-        //                .long("ibed")
-        //                .short("i")
-        //                .takes_value(true)
-        //                .required(true)
-        //                .help("path to the BED file."),
-        //        ),
-        //)
+        .subcommand(
+            SubCommand::with_name("coverage")
+                .about("A subcommand to generate bedGraph/bigWig coverage tracks from fragments")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .takes_value(true)
+                        .required_unless("chromsizes")
+                        .help("path to the BAM file for the chromosome names and sizes."),
+                )
+                .arg(
+                    Arg::with_name("chromsizes")
+                        .long("chromsizes")
+                        .takes_value(true)
+                        .help("path to the chromosome sizes file, in the BAM header order."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output track."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["bedgraph", "bigwig"])
+                        .default_value("bedgraph")
+                        .help("format of the output track, bigwig needs bedGraphToBigWig."),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .possible_values(&["fragment", "insertion"])
+                        .default_value("fragment")
+                        .help("fragment pileup or Tn5 cut site coverage."),
+                )
+                .arg(
+                    Arg::with_name("binsize")
+                        .long("binsize")
+                        .takes_value(true)
+                        .default_value("50")
+                        .help("size of the bins in bp."),
+                )
+                .arg(
+                    Arg::with_name("norm")
+                        .long("norm")
+                        .takes_value(true)
+                        .possible_values(&["none", "cpm", "rpkm", "spikein"])
+                        .default_value("none")
+                        .help("normalization of the coverage, rpkm only for the insertion mode."),
+                )
+                .arg(
+                    Arg::with_name("spikein")
+                        .long("spikein")
+                        .takes_value(true)
+                        .required_if("norm", "spikein")
+                        .help("path to the spike-in counts TSV from bwa/filter."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pseudobulk")
+                .about("A subcommand to split the fragments by barcode groups w/ a track per group")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("groups")
+                        .long("groups")
+                        .short("g")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the barcode to group TSV file."),
+                )
+                .arg(
+                    Arg::with_name("odir")
+                        .long("odir")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output directory."),
+                )
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .takes_value(true)
+                        .required_unless("chromsizes")
+                        .help("path to the BAM file for the chromosome names and sizes."),
+                )
+                .arg(
+                    Arg::with_name("chromsizes")
+                        .long("chromsizes")
+                        .takes_value(true)
+                        .help("path to the chromosome sizes file, in the BAM header order."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["bedgraph", "bigwig"])
+                        .default_value("bigwig")
+                        .help("format of the output tracks, bigwig needs bedGraphToBigWig."),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .possible_values(&["fragment", "insertion"])
+                        .default_value("fragment")
+                        .help("fragment pileup or Tn5 cut site coverage."),
+                )
+                .arg(
+                    Arg::with_name("binsize")
+                        .long("binsize")
+                        .takes_value(true)
+                        .default_value("50")
+                        .help("size of the bins in bp."),
+                )
+                .arg(
+                    Arg::with_name("norm")
+                        .long("norm")
+                        .takes_value(true)
+                        .possible_values(&["none", "cpm", "rpkm", "spikein"])
+                        .default_value("cpm")
+                        .help("normalization of the coverage per group, rpkm only for the insertion mode."),
+                )
+                .arg(
+                    Arg::with_name("spikein")
+                        .long("spikein")
+                        .takes_value(true)
+                        .required_if("norm", "spikein")
+                        .help("path to the spike-in counts TSV from bwa/filter."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("demux")
+                .about("A subcommand to split the fragments into per sample files by barcode")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("samples")
+                        .long("samples")
+                        .short("s")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the barcode to sample TSV file."),
+                )
+                .arg(
+                    Arg::with_name("odir")
+                        .long("odir")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output directory."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["binary", "text", "cb_text"])
+                        .default_value("binary")
+                        .help("format of the output fragment files."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("aggregate")
+                .about("A subcommand to merge sorted fragment files w/ a barcode suffix per library")
+                .arg(
+                    Arg::with_name("ibeds")
+                        .long("ibeds")
+                        .short("i")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                        .help("paths to the sorted binary fragment files, one per library."),
+                )
+                .arg(
+                    Arg::with_name("obed")
+                        .long("obed")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("normalize")
+                        .long("normalize")
+                        .help("down-sample the libraries to the same median fragments per cell."),
+                )
+                .arg(
+                    Arg::with_name("minfrags")
+                        .long("minfrags")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("minimum fragments of a barcode to be a cell for the median."),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("seed of the down-sampling."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("subsample")
+                .about("A subcommand to down-sample the fragments w/ a seeded hash")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("obed")
+                        .long("obed")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output binary fragment file."),
+                )
+                .arg(
+                    Arg::with_name("fraction")
+                        .long("fraction")
+                        .takes_value(true)
+                        .help("fraction of the fragments to keep."),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .help("total number of fragments to keep."),
+                )
+                .arg(
+                    Arg::with_name("maxpercell")
+                        .long("maxpercell")
+                        .takes_value(true)
+                        .help("maximum number of fragments to keep per barcode."),
+                )
+                .group(
+                    ArgGroup::with_name("method")
+                        .args(&["fraction", "target", "maxpercell"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("seed of the hash."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("complexity")
+                .about("A subcommand to estimate the library complexity and saturation")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the binary fragment file, before deduplication."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("prefix of the output complexity files."),
+                )
+                .arg(
+                    Arg::with_name("minfrags")
+                        .long("minfrags")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("minimum unique fragments of a barcode to be a cell."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doublets")
+                .about("A subcommand to call doublets from the fragments overlapping in >2 copies")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the sorted binary fragment file, after deduplication."),
+                )
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .takes_value(true)
+                        .required_unless("chromsizes")
+                        .help("path to the BAM file for the chromosome names and sizes."),
+                )
+                .arg(
+                    Arg::with_name("chromsizes")
+                        .long("chromsizes")
+                        .takes_value(true)
+                        .help("path to the chromosome sizes file, in the BAM header order."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output doublets TSV file."),
+                )
+                .arg(
+                    Arg::with_name("blacklist")
+                        .long("blacklist")
+                        .takes_value(true)
+                        .help("path to a BED file of repeat/blacklist regions to ignore."),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .default_value("chrM,chrX,chrY")
+                        .help("contig names or regexes to ignore, for non diploid chromosomes."),
+                )
+                .arg(
+                    Arg::with_name("minfrags")
+                        .long("minfrags")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("minimum fragments of a barcode to be a cell."),
+                )
+                .arg(
+                    Arg::with_name("qvalue")
+                        .long("qvalue")
+                        .takes_value(true)
+                        .default_value("0.01")
+                        .help("maximum q-value to call a cell a doublet."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("multiplets")
+                .about("A subcommand to merge the barcode multiplets sharing identical fragments")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the sorted binary fragment file, after deduplication."),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output barcode translation TSV file."),
+                )
+                .arg(
+                    Arg::with_name("obed")
+                        .long("obed")
+                        .takes_value(true)
+                        .help("path to the output binary fragment file w/ the merged barcodes."),
+                )
+                .arg(
+                    Arg::with_name("minfrags")
+                        .long("minfrags")
+                        .takes_value(true)
+                        .default_value("1000")
+                        .help("minimum fragments of a barcode to be a cell."),
+                )
+                .arg(
+                    Arg::with_name("minshared")
+                        .long("minshared")
+                        .takes_value(true)
+                        .default_value("3")
+                        .help("minimum shared fragments of a barcode pair to be tested."),
+                )
+                .arg(
+                    Arg::with_name("qvalue")
+                        .long("qvalue")
+                        .takes_value(true)
+                        .default_value("0.01")
+                        .help("maximum q-value to merge a barcode pair."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("filter")
+                .about("A subcommand to filter BAM and generate BED.")
+                .arg(
+                    Arg::with_name("ibam")
+                        .long("ibam")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BAM file"),
+                )
+                .arg(
+                    Arg::with_name("obed")
+                        .long("obed")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the output bed file"),
+                )
+                .arg(
+                    Arg::with_name("tenx")
+                        .long("tenx")
+                        .help("use tag CB from 10x generated BAM."),
+                )
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .help("Don't write the output BED, just produce stats."),
+                )
+                .arg(
+                    Arg::with_name("mitostr")
+                        .long("mitostr")
+                        .short("m")
+                        .takes_value(true)
+                        .default_value("chrM")
+                        .help("String to identify the mitochondrial chromosome"),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("contig names or regexes to keep, e.g. chr[0-9XY]+"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("contig names or regexes to skip, e.g. chrM,chrEBV,.*_random"),
+                )
+                .arg(
+                    Arg::with_name("spikein")
+                        .long("spikein")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .requires("ospikein")
+                        .help("spike-in contig names or regexes, e.g. E.coli or dm.*"),
+                )
+                .arg(
+                    Arg::with_name("ospikein")
+                        .long("ospikein")
+                        .takes_value(true)
+                        .requires("spikein")
+                        .help("path to the output TSV w/ per cell spike-in reads and scale."),
+                )
+                .arg(
+                    Arg::with_name("multimap")
+                        .long("multimap")
+                        .takes_value(true)
+                        .possible_values(&["strict", "primary", "mapq", "random", "flag"])
+                        .default_value("strict")
+                        .help("how to handle multimapping reads."),
+                )
+                .arg(
+                    Arg::with_name("mapq")
+                        .long("mapq")
+                        .takes_value(true)
+                        .default_value("30")
+                        .help("minimum MAPQ of both the mates."),
+                )
+                .arg(
+                    Arg::with_name("orphans")
+                        .long("orphans")
+                        .help("keep orphan and single-end reads as single Tn5 insertions."),
+                )
+                .arg(
+                    Arg::with_name("rejects")
+                        .long("rejects")
+                        .takes_value(true)
+                        .help("path to the BAM (or .tsv) file w/ rejected reads and reason."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("correct")
+                .about("A subcommand to sequence correct the cb sequences.")
+                .arg(
+                    Arg::with_name("whitelist")
+                        .long("whitelist")
+                        .short("w")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the list of known whitelist CB."),
+                )
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file with CB sequences."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sort")
+                .about("A subcommand to sort the file by a chromosome names.")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file with CB sequences."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("group")
+                .about("A subcommand to group the file by (chr, start, end, CB)")
+                .arg(
+                    Arg::with_name("allcb")
+                        .long("allcb")
+                        .help("report all cb instead of count."),
+                )
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file with CB sequences."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("callpeak")
+                .about("A subcommand to call peaks from a grouped bed file")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the grouped BED file."),
+                )
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the bam file with the chromosome names."),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .takes_value(true)
+                        .possible_values(&["binary", "narrowpeak", "bed6"])
+                        .default_value("binary")
+                        .help("output format of the peak file."),
+                )
+                .arg(
+                    Arg::with_name("window")
+                        .long("window")
+                        .short("w")
+                        .takes_value(true)
+                        .help("half width of the window around the summit used for calling."),
+                )
+                .arg(
+                    Arg::with_name("width")
+                        .long("width")
+                        .takes_value(true)
+                        .requires("bam")
+                        .help("re-centre every peak on its summit with this fixed width."),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .short("t")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("number of threads used to call peaks."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("A subcommand to generate peak v cell count matrix")
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the bam file with the chromosome names."),
+                )
+                .arg(
+                    Arg::with_name("pbed")
+                        .long("pbed")
+                        .short("p")
+                        .takes_value(true)
+                        .required_unless_one(&["bed", "gtf", "binsize"])
+                        .help("path to the BED file with the annotated peaks."),
+                )
+                .arg(
+                    Arg::with_name("bed")
+                        .long("bed")
+                        .takes_value(true)
+                        .conflicts_with_all(&["pbed", "gtf", "binsize"])
+                        .requires("bam")
+                        .help("path to a text BED file with the features to count."),
+                )
+                .arg(
+                    Arg::with_name("gtf")
+                        .long("gtf")
+                        .takes_value(true)
+                        .conflicts_with_all(&["pbed", "bed", "binsize"])
+                        .requires("bam")
+                        .help("path to the GTF file with the gene annotation."),
+                )
+                .arg(
+                    Arg::with_name("gtfmode")
+                        .long("gtfmode")
+                        .takes_value(true)
+                        .possible_values(&["genebody", "promoter"])
+                        .default_value("genebody")
+                        .help("count gene body w/ upstream or the promoter of GTF genes."),
+                )
+                .arg(
+                    Arg::with_name("binsize")
+                        .long("binsize")
+                        .takes_value(true)
+                        .conflicts_with_all(&["pbed", "bed", "gtf"])
+                        .requires("bam")
+                        .help("size of the genome wide bins to count."),
+                )
+                .arg(
+                    Arg::with_name("odir")
+                        .long("odir")
+                        .short("o")
+                        .takes_value(true)
+                        .help("path to the output directory, defaults to the CB BED directory."),
+                )
+                .arg(
+                    Arg::with_name("layout")
+                        .long("layout")
+                        .short("l")
+                        .takes_value(true)
+                        .possible_values(&["legacy", "tenx", "h5", "h5ad"])
+                        .default_value("legacy")
+                        .help("layout of the output count matrix, h5 and h5ad need the hdf5 feature."),
+                )
+                .arg(
+                    Arg::with_name("spikein")
+                        .long("spikein")
+                        .takes_value(true)
+                        .help("spike-in counts TSV from bwa/filter, adds scale factors to the obs."),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .possible_values(&["insertion", "fragment", "unique"])
+                        .default_value("unique")
+                        .help("count Tn5 insertions, fragments w/ any or w/ unique overlap."),
+                )
+                .arg(
+                    Arg::with_name("mincells")
+                        .long("mincells")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("minimum number of cells a feature has to be seen in."),
+                )
+                .arg(
+                    Arg::with_name("minfrags")
+                        .long("minfrags")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("minimum number of fragments per cell."),
+                )
+                .arg(
+                    Arg::with_name("whitelist")
+                        .long("whitelist")
+                        .short("w")
+                        .takes_value(true)
+                        .help("path to the list of cell barcodes to retain."),
+                )
+                .arg(
+                    Arg::with_name("cbed")
+                        .long("cbed")
+                        .short("c")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file fragments and CB."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("text")
+                .about("A subcommand to convert binary bed to text.")
+                .arg(
+                    Arg::with_name("cbtext")
+                        .long("cbtext")
+                        .help("writes the last column as CB sequence."),
+                )
+                .arg(
+                    Arg::with_name("bam")
+                        .long("bam")
+                        .short("b")
+                        .takes_value(true)
+                        .help("path to the bam file with the chromosome names."),
+                )
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file with CB sequences."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("A subcommand to summary stats of the binary bed.")
+                .arg(
+                    Arg::with_name("ibed")
+                        .long("ibed")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("path to the BED file."),
+                ),
+        )
         .get_matches();
     pretty_env_logger::init_timed();
 
@@ -266,30 +981,54 @@ fn main() -> Result<(), Box<dyn Error>> {
     if let Some(sub_m) = matches.subcommand_matches("extract") {
         io::fastq::callback(&sub_m)?
     }
-    //if let Some(sub_m) = matches.subcommand_matches("filter") {
-    //    quantify::filter::filter(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("correct") {
-    //    quantify::barcode::correct(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("sort") {
-    //    quantify::sort::sort(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("group") {
-    //    quantify::group::dedup(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("callpeak") {
-    //    quantify::peak::callpeak(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("count") {
-    //    quantify::count::count(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("text") {
-    //    quantify::text::convert(&sub_m)?
-    //}
-    //if let Some(sub_m) = matches.subcommand_matches("stats") {
-    //    quantify::stats::stats(&sub_m)?
-    //}
+    if let Some(sub_m) = matches.subcommand_matches("coverage") {
+        fragments::coverage::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("pseudobulk") {
+        fragments::pseudobulk::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("demux") {
+        fragments::demux::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("aggregate") {
+        fragments::aggregate::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("subsample") {
+        fragments::subsample::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("complexity") {
+        fragments::complexity::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("doublets") {
+        fragments::doublets::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("multiplets") {
+        fragments::multiplets::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("filter") {
+        io::bam::callback(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("correct") {
+        preprocess::barcode::correct(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("sort") {
+        preprocess::sort::sort(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("group") {
+        preprocess::group::dedup(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("callpeak") {
+        preprocess::peak::callpeak(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("count") {
+        preprocess::count::count(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("text") {
+        preprocess::text::convert(&sub_m)?
+    }
+    if let Some(sub_m) = matches.subcommand_matches("stats") {
+        preprocess::stats::stats(&sub_m)?
+    }
 
     Ok(())
 }
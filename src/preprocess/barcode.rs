@@ -8,7 +8,7 @@ use std::path::Path;
 
 use carina::barcode::*;
 
-use crate::quantify::fragments::Fragment;
+use crate::fragments::schema::Fragment;
 use num_format::{Locale, ToFormattedString};
 use std::collections::HashSet;
 
@@ -66,7 +66,10 @@ pub fn correct(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     while let Ok(frag) = Fragment::read(&mut input_bed, &mut mem_block) {
         num_lines += 1;
         if num_lines % crate::configs::TMIL == 0 {
-            print!("\rDone processing {}0M reads", num_lines / crate::configs::TMIL);
+            print!(
+                "\rDone processing {}0M reads",
+                num_lines / crate::configs::TMIL
+            );
             std::io::stdout().flush().expect("Can't flush output");
         }
 
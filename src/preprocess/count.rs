@@ -1,218 +1,672 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::Write;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::io::{Read as IoRead, Write};
 use std::ops::Range;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::quantify::fragments::FragmentFile;
+use crate::fragments::schema::{cb_from_string, cb_to_string, FragmentFile};
+use crate::fragments::spikein::SpikeInCounts;
+use crate::preprocess::feature_set::FeatureSet;
+use crate::preprocess::matrix::{check_layout, CountMatrix, MatrixEntry};
 use bio::data_structures::interval_tree::{Entry, IntervalTree};
 use clap::ArgMatches;
 use itertools::Itertools;
-use sprs::TriMat;
+use serde::{Deserialize, Serialize};
 
 use crate::rust_htslib::bam::Read;
 use num_format::{Locale, ToFormattedString};
 use rust_htslib::bam;
-use carina::barcode::*;
+
+// (raw row, cb, count) entries of the matrix spilled to disk per chromosome
+#[derive(Serialize, Deserialize, Debug)]
+struct Triplet {
+    row: u32,
+    cb: u64,
+    count: u32,
+}
+
+impl Triplet {
+    fn write(&self, file: &mut BufWriter<File>) -> Result<(), Box<dyn Error>> {
+        let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
+        file.write_all(&encoded)?;
+        Ok(())
+    }
+}
+
+struct TripletFile {
+    buf: BufReader<File>,
+    mem_block: [u8; 16],
+}
+
+impl TripletFile {
+    fn new(buf: BufReader<File>) -> TripletFile {
+        TripletFile {
+            buf,
+            mem_block: [0; 16],
+        }
+    }
+}
+
+impl Iterator for TripletFile {
+    type Item = Triplet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.read_exact(&mut self.mem_block).ok()?;
+        bincode::deserialize(&self.mem_block[..]).ok()
+    }
+}
+
+// per barcode fragments, counted units (fragments or insertions) and the
+// units overlapping any feature, the FRiP uses the same unit for both
+#[derive(Default)]
+struct CellCounts {
+    frags: u32,
+    units: u32,
+    feature_units: u32,
+}
+
+impl CellCounts {
+    fn add(&mut self, num_units: u32, num_feature_units: u32) {
+        self.frags += 1;
+        self.units += num_units;
+        self.feature_units += num_feature_units;
+    }
+}
+
+// insertion mode counts both the cut sites of a fragment and the single
+// one of a half-fragment (orphan/single-end read)
+fn num_units(start: u64, end: u64, count_mode: &str) -> u32 {
+    match (count_mode, end > start + 1) {
+        ("insertion", true) => 2,
+        _ => 1,
+    }
+}
+
+// features a fragment is counted in, the units (fragment or cut sites)
+// overlapping any feature and the ones w/o an overlap, unique mode drops
+// the fragments overlapping more than one feature
+struct FragmentHits {
+    feat_indices: Vec<usize>,
+    feature_units: u32,
+    no_overlap_units: usize,
+    is_multi: bool,
+}
+
+fn fragment_hits(
+    range: &Range<u32>,
+    features_tree: &IntervalTree<u32, usize>,
+    count_mode: &str,
+) -> FragmentHits {
+    let mut hits = FragmentHits {
+        feat_indices: Vec::new(),
+        feature_units: 0,
+        no_overlap_units: 0,
+        is_multi: false,
+    };
+
+    match count_mode {
+        // each Tn5 cut site is counted independently
+        "insertion" => {
+            // a half-fragment (orphan/single-end read) is a single cut site
+            let mut cut_sites = vec![range.start];
+            if range.end > range.start + 1 {
+                cut_sites.push(range.end - 1);
+            }
+
+            for cut_site in &cut_sites {
+                let overlapping_features: Vec<Entry<u32, usize>> =
+                    features_tree.find(*cut_site..*cut_site + 1).collect();
+                match overlapping_features.is_empty() {
+                    true => hits.no_overlap_units += 1,
+                    false => hits.feature_units += 1,
+                };
+                hits.feat_indices
+                    .extend(overlapping_features.iter().map(|x| *x.data()));
+            }
+        }
+        // fragment is counted once in every feature it overlaps
+        "fragment" => {
+            let overlapping_features: Vec<Entry<u32, usize>> =
+                features_tree.find(range.clone()).collect();
+            match overlapping_features.is_empty() {
+                true => hits.no_overlap_units += 1,
+                false => hits.feature_units += 1,
+            };
+            hits.feat_indices = overlapping_features.iter().map(|x| *x.data()).collect();
+        }
+        // fragment is counted only if it overlaps exactly one feature
+        "unique" => {
+            let overlapping_features: Vec<Entry<u32, usize>> =
+                features_tree.find(range.clone()).collect();
+            match overlapping_features.len() {
+                0 => hits.no_overlap_units += 1,
+                1 => {
+                    hits.feature_units += 1;
+                    hits.feat_indices = vec![*overlapping_features.first().unwrap().data()];
+                }
+                _ => {
+                    hits.feature_units += 1;
+                    hits.is_multi = true;
+                }
+            };
+        }
+        _ => unreachable!(),
+    };
+
+    hits
+}
+
+// The triplets are spilled in increasing order of the raw rows, one sorted
+// run per chromosome. Mapping them to the final rows and columns is a single
+// sequential pass, the entries go back to disk in row order and only the
+// number of entries per column is kept in memory.
+fn spill_entries(
+    triplet_file_path: &Path,
+    row_map: &[Option<usize>],
+    col_map: &HashMap<u64, usize>,
+    entries_path: &Path,
+) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut col_nnz = vec![0; col_map.len()];
+    let mut entries_file = BufWriter::new(File::create(entries_path)?);
+
+    let mut last_row = 0;
+    let triplets = TripletFile::new(BufReader::new(File::open(triplet_file_path)?));
+    for triplet in triplets {
+        let row = match row_map[triplet.row as usize] {
+            Some(row) => row,
+            None => continue,
+        };
+        let col = match col_map.get(&triplet.cb) {
+            Some(col) => *col,
+            None => continue,
+        };
+
+        if row < last_row {
+            return Err("fragments have to be sorted by chromosome, run sort first".into());
+        }
+        last_row = row;
+
+        col_nnz[col] += 1;
+        MatrixEntry {
+            row: row as u32,
+            col: col as u32,
+            count: triplet.count,
+        }
+        .write(&mut entries_file)?;
+    }
+    entries_file.flush()?;
+
+    Ok(col_nnz)
+}
 
 pub fn count(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
-    let bed_file_path = Path::new(sub_m.value_of("pbed").expect("can't find peak BED flag"))
-        .canonicalize()
-        .expect("can't find absolute path of input peak BED file");
-    info!("Found BED file: {:?}", bed_file_path);
-    let input_bed =
-        BufReader::new(File::open(bed_file_path.clone()).expect("Can't open peak BED file"));
+    let bam_header = match sub_m.value_of("bam") {
+        Some(path) => {
+            let bam_file_path = Path::new(path)
+                .canonicalize()
+                .expect("can't find absolute path of input BAM file");
+            info!("Found BAM file: {:?}", bam_file_path);
+
+            let input_bam = bam::Reader::from_path(bam_file_path).expect("Can't open BAM file");
+            Some(input_bam.header().clone())
+        }
+        None => None,
+    };
+
+    let layout = sub_m.value_of("layout").unwrap_or("legacy");
+    check_layout(layout)?;
+
+    let feature_set = FeatureSet::from_clap(sub_m, bam_header.as_ref())?;
 
     let cbed_file_path = Path::new(sub_m.value_of("cbed").expect("can't find CB BED flag"))
         .canonicalize()
         .expect("can't find absolute path of input cb BED file");
     info!("Found BED file: {:?}", cbed_file_path);
-    let cb_input_bed = BufReader::new(File::open(cbed_file_path).expect("Can't open CB BED file"));
+    let cb_input_bed =
+        BufReader::new(File::open(cbed_file_path.clone()).expect("Can't open CB BED file"));
 
-    let frag_group = FragmentFile::new(input_bed)
-        .map(|maybe_frag| maybe_frag)
-        .group_by(|frag| frag.chr);
-    let mut frag_iter = frag_group.into_iter();
+    let out_dir = match sub_m.value_of("odir") {
+        Some(path) => PathBuf::from(path),
+        None => cbed_file_path.parent().unwrap().to_path_buf(),
+    };
+    std::fs::create_dir_all(&out_dir)?;
 
-    let frag_cb_group = FragmentFile::new(cb_input_bed)
-        .map(|maybe_frag| maybe_frag)
-        .group_by(|frag| frag.chr);
-    let mut frag_cb_iter = frag_cb_group.into_iter();
+    let triplet_file_path = out_dir.join("counts.triplets.tmp");
+    let mut triplet_file = BufWriter::new(File::create(&triplet_file_path)?);
+
+    // per barcode counts for the QC columns, the mitochondrial reads are
+    // already dropped by the filter
+    let mut cb_frags: HashMap<u64, CellCounts> = HashMap::new();
 
+    let count_mode = sub_m.value_of("mode").unwrap_or("unique");
+    let unit_name = match count_mode {
+        "insertion" => "insertions",
+        _ => "fragments",
+    };
+    info!("Counting {} overlapping the features", unit_name);
+
+    let mut no_overlap_skip = 0;
+    let mut multi_overlap_skip = 0;
     let mut total_reads = 0;
-    let mut skipped_chr_indices = Vec::new();
-    let mut counts: Vec<HashMap<Range<u32>, HashMap<u64, u16>>> = Vec::new();
-    while let (Some(chr_frag), Some(chr_cb_frag)) = (frag_iter.next(), frag_cb_iter.next()) {
-        let (chr, chr_group) = chr_frag;
-        let (chr_cb, chr_cb_group) = chr_cb_frag;
-        if chr != chr_cb {
-            warn!(
-                "Early breaking after {} as some chromosomes does not appear in both files",
-                chr
-            );
-            break;
-        }
+    let row_offsets = feature_set.row_offsets();
+    let mut raw_rows: Vec<usize> = Vec::new();
+    let mut raw_num_cells: Vec<u32> = Vec::new();
+    let mut matrix_cbs: HashSet<u64> = HashSet::new();
+    for (chr, chr_cb_group) in FragmentFile::new(cb_input_bed)
+        .map(|maybe_frag| maybe_frag)
+        .group_by(|frag| frag.chr)
+        .into_iter()
+    {
+        let chr_features = match feature_set.chr_features(chr) {
+            Some(chr_features) => chr_features,
+            None => {
+                chr_cb_group.for_each(|frag| {
+                    let num_units = num_units(frag.start, frag.end, count_mode);
+                    cb_frags
+                        .entry(frag.cb)
+                        .or_insert_with(CellCounts::default)
+                        .add(num_units, 0);
+                    no_overlap_skip += num_units as usize;
+                });
+                continue;
+            }
+        };
 
-        skipped_chr_indices.push(chr);
         print!("\rWorking on Chromosome: {}", chr);
         std::io::stdout().flush().expect("Can't flush output");
 
-        let peaks: Vec<Range<u32>> = chr_group
-            .map(|x| Range {
-                start: x.start as u32,
-                end: x.end as u32,
-            })
-            .collect();
-
-        // making interval tree for the peaks to find the overlap
-        let mut peaks_tree = IntervalTree::new();
-        for (peak_idx, peak) in peaks.iter().enumerate() {
+        // making interval tree for the features to find the overlap
+        let mut features_tree = IntervalTree::new();
+        for (feat_idx, feature) in chr_features.iter().enumerate() {
             assert!(
-                peak.start < peak.end,
-                format!("{}, {}", peak.start, peak.end)
+                feature.range.start < feature.range.end,
+                "{}, {}",
+                feature.range.start,
+                feature.range.end
             );
-            peaks_tree.insert(peak, peak_idx);
+            features_tree.insert(feature.range.clone(), feat_idx);
         }
 
-        counts.push(HashMap::new());
-        let count_matrix = counts.last_mut().unwrap();
-
+        let mut count_matrix: HashMap<usize, HashMap<u64, u32>> = HashMap::new();
         for (range, class) in &chr_cb_group.group_by(|frag| Range {
             start: frag.start as u32,
             end: frag.end as u32,
         }) {
-            let overlapping_peaks: Vec<Entry<u32, usize>> = peaks_tree.find(range).collect();
-            if overlapping_peaks.len() != 1 {
-                continue;
+            let cb_group: Vec<u64> = class.map(|x| x.cb).collect();
+            let num_cb = cb_group.len();
+            let hits = fragment_hits(&range, &features_tree, count_mode);
+            no_overlap_skip += hits.no_overlap_units * num_cb;
+            if hits.is_multi {
+                multi_overlap_skip += num_cb;
             }
 
-            let intv = overlapping_peaks.first().unwrap().interval();
-            let peak = Range {
-                start: intv.start,
-                end: intv.end,
-            };
-
-            let cb_group: Vec<u64> = class.map(|x| x.cb).collect();
+            let num_units = num_units(range.start as u64, range.end as u64, count_mode);
             for cb in cb_group {
-                let val = count_matrix
-                    .entry(peak.clone())
-                    .or_insert_with(HashMap::new)
+                cb_frags
                     .entry(cb)
-                    .or_insert(0);
-                *val += 1;
-                total_reads += 1;
+                    .or_insert_with(CellCounts::default)
+                    .add(num_units, hits.feature_units);
+
+                for feat_idx in &hits.feat_indices {
+                    let val = count_matrix
+                        .entry(*feat_idx)
+                        .or_insert_with(HashMap::new)
+                        .entry(cb)
+                        .or_insert(0);
+                    *val += 1;
+                    total_reads += 1;
+                }
             }
         } // end-for
-    } // end-while
+
+        // spilling the chromosome's counts to disk
+        let mut feat_indices: Vec<usize> = count_matrix.keys().cloned().collect();
+        feat_indices.sort_unstable();
+        for feat_idx in feat_indices {
+            let row = raw_rows.len() as u32;
+            raw_rows.push(row_offsets[&chr] + feat_idx);
+
+            let mut row_data: Vec<(u64, u32)> = count_matrix
+                .remove(&feat_idx)
+                .unwrap()
+                .into_iter()
+                .collect();
+            row_data.sort_unstable();
+            raw_num_cells.push(row_data.len() as u32);
+
+            for (cb, count) in row_data {
+                matrix_cbs.insert(cb);
+                Triplet { row, cb, count }.write(&mut triplet_file)?;
+            }
+        }
+    } // end-for
+    triplet_file.flush()?;
+    drop(triplet_file);
 
     println!();
     info!(
-        "Found total {} reads in the matrix",
-        total_reads.to_formatted_string(&Locale::en)
+        "Found total {} {} in the matrix",
+        total_reads.to_formatted_string(&Locale::en),
+        unit_name
     );
+    info!(
+        "Discarded {} {} w/o any overlapping feature",
+        no_overlap_skip.to_formatted_string(&Locale::en),
+        unit_name
+    );
+    if count_mode == "unique" {
+        info!(
+            "Discarded {} {} overlapping multiple features",
+            multi_overlap_skip.to_formatted_string(&Locale::en),
+            unit_name
+        );
+    }
 
-    let mut col_names: HashMap<u64, usize> = HashMap::new();
-    counts.iter().for_each(|matrix| {
-        matrix.iter().for_each(|(_, row_data)| {
-            row_data.iter().for_each(|(col_id, _)| {
-                if !col_names.contains_key(col_id) {
-                    let name_len = col_names.len();
-                    col_names.insert(*col_id, name_len);
-                }
-            });
-        });
-    });
-
-    let mut row_ids = Vec::new();
-    let mut col_ids = Vec::new();
-    let mut data = Vec::new();
-
-    let bam_header = match sub_m.value_of("bam") {
+    let whitelist: Option<HashSet<u64>> = match sub_m.value_of("whitelist") {
         Some(path) => {
-            let bam_file_path = Path::new(path)
+            let wtl_file_path = Path::new(path)
                 .canonicalize()
-                .expect("can't find absolute path of input BAM file");
-            info!("Found BAM file: {:?}", bam_file_path);
+                .expect("can't find absolute path of input whitelist file");
+            info!("Found whitelist CB file: {:?}", wtl_file_path);
 
-            let input_bam = bam::Reader::from_path(bam_file_path).expect("Can't open BAM file");
-            Some(input_bam.header().clone())
+            let mut wtl_barcodes = HashSet::new();
+            // the -N library suffix matches the barcodes of an aggregated file
+            for cb_str in BufReader::new(File::open(wtl_file_path)?).lines() {
+                let cb_str = cb_str?;
+                if cb_str.trim().is_empty() {
+                    continue;
+                }
+                wtl_barcodes.insert(cb_from_string(cb_str.trim())?);
+            }
+            Some(wtl_barcodes)
         }
         None => None,
     };
+    let min_frags: u32 = sub_m
+        .value_of("minfrags")
+        .unwrap_or("0")
+        .parse()
+        .expect("can't parse minimum fragments per cell");
+    let min_cells: usize = sub_m
+        .value_of("mincells")
+        .unwrap_or("0")
+        .parse()
+        .expect("can't parse minimum cells per feature");
 
-    let mut row_names: HashMap<String, usize> = HashMap::new();
-    for (chr_idx, count) in counts.into_iter().enumerate() {
-        let skip_chr_id = skipped_chr_indices[chr_idx];
-        let chr_name = skip_chr_id.to_string();
-        for (row_range, row_data) in count {
-            if row_data.len() < 10 {
-                continue;
+    // every whitelisted barcode, or every barcode of the fragment file, is
+    // a column unless it is removed by the minimum fragments filter. The
+    // cells are filtered before the features, so that the minimum cells per
+    // feature is computed only over the retained cells.
+    let (candidate_cbs, wtl_skip): (Vec<u64>, usize) = match whitelist {
+        Some(wtl_barcodes) => {
+            let wtl_skip = cb_frags
+                .keys()
+                .filter(|cb| !wtl_barcodes.contains(cb))
+                .count();
+            (wtl_barcodes.into_iter().collect(), wtl_skip)
+        }
+        None => (cb_frags.keys().cloned().collect(), 0),
+    };
+    let num_total_cells = candidate_cbs.len();
+    let mut min_frags_skip = 0;
+    let mut kept_cbs: Vec<u64> = candidate_cbs
+        .into_iter()
+        .filter(|cb| {
+            let num_frags = cb_frags.get(cb).map_or(0, |x| x.frags);
+            if num_frags < min_frags {
+                min_frags_skip += 1;
+                return false;
             }
+            true
+        })
+        .collect();
+    kept_cbs.sort_unstable();
 
-            let mut row_name = match &bam_header {
-                Some(header) => std::str::from_utf8(header.tid2name(skip_chr_id))
-                    .unwrap()
-                    .to_string(),
-                None => chr_name.clone(),
-            };
-            row_name.push_str("_");
-            row_name.push_str(&row_range.start.to_string());
-            row_name.push_str(":");
-            row_name.push_str(&row_range.end.to_string());
-
-            let row_id = match row_names.contains_key(&row_name) {
-                true => *row_names.get(&row_name).unwrap(),
-                false => {
-                    let name_len = row_names.len();
-                    row_names.insert(row_name, name_len);
-                    name_len
-                }
-            };
+    let mut col_names: HashMap<u64, usize> = HashMap::new();
+    for (col_id, cb) in kept_cbs.into_iter().enumerate() {
+        col_names.insert(cb, col_id);
+    }
 
-            for (col_name, col_data) in row_data {
-                let col_id = col_names.get(&col_name).unwrap();
-                row_ids.push(row_id);
-                col_ids.push(*col_id);
-                data.push(col_data);
+    // the cells per feature have to be recounted if any cell was removed
+    if matrix_cbs.iter().any(|cb| !col_names.contains_key(cb)) {
+        raw_num_cells.iter_mut().for_each(|x| *x = 0);
+        let triplets = TripletFile::new(BufReader::new(File::open(&triplet_file_path)?));
+        for triplet in triplets {
+            if col_names.contains_key(&triplet.cb) {
+                raw_num_cells[triplet.row as usize] += 1;
             }
         }
     }
 
-    let matrix =
-        TriMat::from_triplets((row_names.len(), col_names.len()), row_ids, col_ids, data).to_csr();
+    // every feature is a row unless it is removed by the minimum cells
+    // filter, including the features w/o any count
+    let all_features = feature_set.into_rows();
+    let num_total_features = all_features.len();
+    let mut feature_num_cells: Vec<u32> = vec![0; num_total_features];
+    for (raw_row, num_cells) in raw_rows.iter().zip(raw_num_cells.iter()) {
+        feature_num_cells[*raw_row] = *num_cells;
+    }
 
-    let mtx_file_path = bed_file_path.parent().unwrap().join("counts.mtx");
+    let mut min_cells_skip = 0;
+    let mut feature_rows: Vec<Option<usize>> = Vec::with_capacity(num_total_features);
+    let mut row_features = Vec::new();
+    for (feature, num_cells) in all_features.into_iter().zip(feature_num_cells) {
+        if (num_cells as usize) < min_cells {
+            min_cells_skip += 1;
+            feature_rows.push(None);
+            continue;
+        }
 
-    info!("Creating output MTX file: {:?}", mtx_file_path);
-    sprs::io::write_matrix_market(mtx_file_path, &matrix)?;
+        feature_rows.push(Some(row_features.len()));
+        row_features.push(feature);
+    }
+    let row_map: Vec<Option<usize>> = raw_rows.iter().map(|x| feature_rows[*x]).collect();
 
-    {
-        let rows_file_path = bed_file_path.parent().unwrap().join("counts_rows.txt");
-        let mut file = BufWriter::new(File::create(rows_file_path)?);
-        let mut sorted_row_names = vec![String::new(); row_names.len()];
-        row_names.into_iter().for_each(|(k, v)| {
-            sorted_row_names[v] = k;
-        });
-
-        for row_name in sorted_row_names {
-            file.write_all((row_name + "\n").as_bytes())?;
-        }
+    match wtl_skip {
+        0 => info!(
+            "Removed {} cells w/ < {} fragments out of {}",
+            min_frags_skip.to_formatted_string(&Locale::en),
+            min_frags,
+            num_total_cells.to_formatted_string(&Locale::en)
+        ),
+        _ => info!(
+            "Skipped {} barcodes not in whitelist, removed {} whitelisted cells w/ < {} fragments out of {}",
+            wtl_skip.to_formatted_string(&Locale::en),
+            min_frags_skip.to_formatted_string(&Locale::en),
+            min_frags,
+            num_total_cells.to_formatted_string(&Locale::en)
+        ),
+    };
+    info!(
+        "Removed {} features w/ < {} cells out of {}",
+        min_cells_skip.to_formatted_string(&Locale::en),
+        min_cells,
+        num_total_features.to_formatted_string(&Locale::en)
+    );
+
+    let entries_path = out_dir.join("counts.entries.tmp");
+    let col_nnz = spill_entries(&triplet_file_path, &row_map, &col_names, &entries_path)?;
+    std::fs::remove_file(&triplet_file_path)?;
+    let shape = (row_features.len(), col_names.len());
+    info!(
+        "Final matrix has {} features and {} cells",
+        shape.0.to_formatted_string(&Locale::en),
+        shape.1.to_formatted_string(&Locale::en)
+    );
+
+    let mut sorted_cbs = vec![0; col_names.len()];
+    col_names.into_iter().for_each(|(k, v)| sorted_cbs[v] = k);
+    let sorted_col_names: Vec<String> = sorted_cbs
+        .iter()
+        .map(|cb| cb_to_string(*cb))
+        .collect::<Result<_, _>>()?;
+
+    // whitelisted barcodes w/o any fragment have all counts 0
+    let no_counts = CellCounts::default();
+    let cell_counts: Vec<&CellCounts> = sorted_cbs
+        .iter()
+        .map(|cb| cb_frags.get(cb).unwrap_or(&no_counts))
+        .collect();
+    let num_frags: Vec<f32> = cell_counts.iter().map(|x| x.frags as f32).collect();
+    let num_units: Vec<f32> = cell_counts.iter().map(|x| x.units as f32).collect();
+    let num_feature_units: Vec<f32> = cell_counts.iter().map(|x| x.feature_units as f32).collect();
+    let frip: Vec<f32> = num_feature_units
+        .iter()
+        .zip(num_units.iter())
+        .map(|(in_feature, total)| match *total == 0.0 {
+            true => f32::NAN,
+            false => in_feature / total,
+        })
+        .collect();
+
+    let mut obs = vec![("num_fragments".to_string(), num_frags)];
+    if count_mode == "insertion" {
+        obs.push(("num_insertions".to_string(), num_units));
     }
+    obs.push((format!("num_feature_{}", unit_name), num_feature_units));
+    obs.push(("frip".to_string(), frip));
 
-    {
-        let cols_file_path = bed_file_path.parent().unwrap().join("counts_cols.txt");
-        let mut file = BufWriter::new(File::create(cols_file_path)?);
-        let mut sorted_col_names = vec![String::new(); col_names.len()];
-        col_names.into_iter().for_each(|(k, v)| {
-            sorted_col_names[v] = u64_to_cb_string(k).unwrap();
-        });
-
-        for col_name in sorted_col_names {
-            file.write_all((col_name + "\n").as_bytes())?;
+    // spike-in reads and scaling factor per cell, NaN w/o any spike-in read
+    if let Some(path) = sub_m.value_of("spikein") {
+        let spikein = SpikeInCounts::from_file(Path::new(path))?;
+        let mut spikein_reads = vec![0.0; sorted_cbs.len()];
+        let mut spikein_scale = vec![f32::NAN; sorted_cbs.len()];
+        for (idx, cb) in sorted_cbs.iter().enumerate() {
+            spikein_reads[idx] = spikein.cb_count(*cb) as f32;
+            if let Some(scale) = spikein.cb_scale(*cb) {
+                spikein_scale[idx] = scale;
+            }
         }
+
+        obs.push(("spikein_reads".to_string(), spikein_reads));
+        obs.push(("spikein_scale".to_string(), spikein_scale));
     }
 
+    let count_matrix = CountMatrix {
+        entries_path,
+        shape,
+        col_nnz,
+        features: row_features,
+        barcodes: sorted_col_names,
+        obs,
+    };
+
+    info!("Writing {} count matrix into {:?}", layout, out_dir);
+    count_matrix.write(&out_dir, layout)?;
+    std::fs::remove_file(&count_matrix.entries_path)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocess::matrix::EntryFile;
+
+    fn features_tree() -> IntervalTree<u32, usize> {
+        let mut tree = IntervalTree::new();
+        tree.insert(100..200, 0);
+        tree.insert(150..300, 1);
+        tree.insert(500..600, 2);
+        tree
+    }
+
+    fn spill(triplets: &[(u32, u64, u32)], name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir();
+        let triplet_path = dir.join(format!("volans.{}.{}.triplets", name, std::process::id()));
+        let entries_path = dir.join(format!("volans.{}.{}.entries", name, std::process::id()));
+
+        let mut file = BufWriter::new(File::create(&triplet_path).unwrap());
+        for (row, cb, count) in triplets {
+            Triplet {
+                row: *row,
+                cb: *cb,
+                count: *count,
+            }
+            .write(&mut file)
+            .unwrap();
+        }
+        file.flush().unwrap();
+
+        (triplet_path, entries_path)
+    }
+
+    #[test]
+    fn spill_entries_maps_and_keeps_row_order() {
+        let (triplet_path, entries_path) = spill(
+            &[(0, 7, 1), (0, 9, 2), (1, 7, 3), (2, 8, 4), (3, 9, 5)],
+            "order",
+        );
+        let row_map = vec![Some(0), None, Some(1), Some(2)];
+        let col_map: HashMap<u64, usize> = vec![(7, 0), (9, 1)].into_iter().collect();
+
+        let col_nnz = spill_entries(&triplet_path, &row_map, &col_map, &entries_path).unwrap();
+        assert_eq!(col_nnz, vec![1, 2]);
+
+        let entries: Vec<(u32, u32, u32)> =
+            EntryFile::new(BufReader::new(File::open(&entries_path).unwrap()))
+                .map(|x| (x.row, x.col, x.count))
+                .collect();
+        assert_eq!(entries, vec![(0, 0, 1), (0, 1, 2), (2, 1, 5)]);
+
+        std::fs::remove_file(triplet_path).unwrap();
+        std::fs::remove_file(entries_path).unwrap();
+    }
+
+    #[test]
+    fn spill_entries_rejects_unsorted_rows() {
+        let (triplet_path, entries_path) = spill(&[(1, 7, 1), (0, 7, 1)], "unsorted");
+        let row_map = vec![Some(0), Some(1)];
+        let col_map: HashMap<u64, usize> = vec![(7, 0)].into_iter().collect();
+
+        assert!(spill_entries(&triplet_path, &row_map, &col_map, &entries_path).is_err());
+
+        std::fs::remove_file(triplet_path).unwrap();
+        std::fs::remove_file(entries_path).unwrap();
+    }
+
+    #[test]
+    fn num_units_per_mode() {
+        assert_eq!(num_units(100, 200, "insertion"), 2);
+        assert_eq!(num_units(100, 101, "insertion"), 1);
+        assert_eq!(num_units(100, 200, "fragment"), 1);
+        assert_eq!(num_units(100, 200, "unique"), 1);
+    }
+
+    #[test]
+    fn insertion_mode_counts_cut_sites() {
+        let hits = fragment_hits(&(120..550), &features_tree(), "insertion");
+        assert_eq!(hits.feat_indices, vec![0, 2]);
+        assert_eq!((hits.feature_units, hits.no_overlap_units), (2, 0));
+
+        let hits = fragment_hits(&(120..400), &features_tree(), "insertion");
+        assert_eq!(hits.feat_indices, vec![0]);
+        assert_eq!((hits.feature_units, hits.no_overlap_units), (1, 1));
+    }
+
+    #[test]
+    fn fragment_mode_counts_every_overlap() {
+        let mut hits = fragment_hits(&(160..170), &features_tree(), "fragment");
+        hits.feat_indices.sort_unstable();
+        assert_eq!(hits.feat_indices, vec![0, 1]);
+        assert_eq!((hits.feature_units, hits.no_overlap_units), (1, 0));
+
+        let hits = fragment_hits(&(350..400), &features_tree(), "fragment");
+        assert!(hits.feat_indices.is_empty());
+        assert_eq!((hits.feature_units, hits.no_overlap_units), (0, 1));
+    }
+
+    #[test]
+    fn unique_mode_drops_multi_overlaps() {
+        let hits = fragment_hits(&(160..170), &features_tree(), "unique");
+        assert!(hits.feat_indices.is_empty());
+        assert!(hits.is_multi);
+        assert_eq!(hits.feature_units, 1);
+
+        let hits = fragment_hits(&(520..540), &features_tree(), "unique");
+        assert_eq!(hits.feat_indices, vec![2]);
+        assert!(!hits.is_multi);
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::fragments::schema::FragmentFile;
+use clap::ArgMatches;
+use num_format::{Locale, ToFormattedString};
+use rust_htslib::bam::HeaderView;
+
+use crate::configs::{GENE_UPSTREAM, PROMOTER_DOWNSTREAM, PROMOTER_UPSTREAM};
+
+#[derive(Debug, Clone)]
+pub struct CountFeature {
+    pub chr_name: String,
+    pub range: Range<u32>,
+    pub name: String,
+}
+
+impl CountFeature {
+    pub fn interval(&self) -> String {
+        range_name(&self.chr_name, &self.range)
+    }
+}
+
+// Collection of features per chromosome id, sorted by start position,
+// against which the fragments are counted.
+pub struct FeatureSet {
+    features: HashMap<u32, Vec<CountFeature>>,
+}
+
+fn chr_name(header: Option<&HeaderView>, chr: u32) -> String {
+    match header {
+        Some(header) => std::str::from_utf8(header.tid2name(chr))
+            .unwrap()
+            .to_string(),
+        None => chr.to_string(),
+    }
+}
+
+fn range_name(chr_name: &str, range: &Range<u32>) -> String {
+    format!("{}:{}-{}", chr_name, range.start, range.end)
+}
+
+fn open_text_file(path: &str) -> Result<BufReader<File>, Box<dyn Error>> {
+    let file_path = Path::new(path)
+        .canonicalize()
+        .expect("can't find absolute path of input feature file");
+    info!("Found feature file: {:?}", file_path);
+
+    Ok(BufReader::new(File::open(file_path)?))
+}
+
+impl FeatureSet {
+    fn new(mut features: HashMap<u32, Vec<CountFeature>>) -> FeatureSet {
+        features.values_mut().for_each(|chr_features| {
+            chr_features.sort_by(|a, b| a.range.start.cmp(&b.range.start));
+        });
+
+        FeatureSet { features }
+    }
+
+    pub fn from_clap(
+        sub_m: &ArgMatches,
+        header: Option<&HeaderView>,
+    ) -> Result<FeatureSet, Box<dyn Error>> {
+        let feature_set = if let Some(path) = sub_m.value_of("pbed") {
+            FeatureSet::from_peaks(path, header)?
+        } else if let Some(path) = sub_m.value_of("bed") {
+            FeatureSet::from_bed(path, header.expect("BED features need the --bam header"))?
+        } else if let Some(path) = sub_m.value_of("gtf") {
+            let gtf_mode = sub_m.value_of("gtfmode").unwrap_or("genebody");
+            FeatureSet::from_gtf(
+                path,
+                header.expect("GTF features need the --bam header"),
+                gtf_mode,
+            )?
+        } else if let Some(bin_size) = sub_m.value_of("binsize") {
+            let bin_size: u32 = bin_size.parse().expect("can't parse bin size");
+            FeatureSet::from_bins(
+                header.expect("genomic bins need the --bam header"),
+                bin_size,
+            )
+        } else {
+            unreachable!()
+        };
+
+        info!(
+            "Found total {} features to count against",
+            feature_set.num_features().to_formatted_string(&Locale::en)
+        );
+        Ok(feature_set)
+    }
+
+    // peaks generated by the callpeak subcommand in binary format
+    pub fn from_peaks(
+        path: &str,
+        header: Option<&HeaderView>,
+    ) -> Result<FeatureSet, Box<dyn Error>> {
+        let file_path = Path::new(path)
+            .canonicalize()
+            .expect("can't find absolute path of input peak BED file");
+        info!("Found BED file: {:?}", file_path);
+        let input_bed = BufReader::new(File::open(file_path)?);
+
+        let mut features: HashMap<u32, Vec<CountFeature>> = HashMap::new();
+        for frag in FragmentFile::new(input_bed) {
+            let range = Range {
+                start: frag.start as u32,
+                end: frag.end as u32,
+            };
+
+            let chr_name = chr_name(header, frag.chr);
+            let name = range_name(&chr_name, &range);
+            features
+                .entry(frag.chr)
+                .or_insert_with(Vec::new)
+                .push(CountFeature {
+                    chr_name,
+                    range,
+                    name,
+                });
+        }
+
+        Ok(FeatureSet::new(features))
+    }
+
+    // text BED file with the chromosome names in the first column
+    pub fn from_bed(path: &str, header: &HeaderView) -> Result<FeatureSet, Box<dyn Error>> {
+        let mut features: HashMap<u32, Vec<CountFeature>> = HashMap::new();
+        for line in open_text_file(path)?.lines() {
+            let line = line?;
+            if line.starts_with('#') || line.starts_with("track") || line.is_empty() {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.split('\t').collect();
+            if toks.len() < 3 {
+                return Err(format!("BED line w/ less than 3 columns: {}", line).into());
+            }
+
+            let chr = match header.tid(toks[0].as_bytes()) {
+                Some(chr) => chr,
+                None => continue,
+            };
+
+            let range = Range {
+                start: toks[1].parse()?,
+                end: toks[2].parse()?,
+            };
+            let name = range_name(toks[0], &range);
+            features
+                .entry(chr)
+                .or_insert_with(Vec::new)
+                .push(CountFeature {
+                    chr_name: toks[0].to_string(),
+                    range,
+                    name,
+                });
+        }
+
+        Ok(FeatureSet::new(features))
+    }
+
+    // gene records of a GTF file, either the gene body extended upstream or
+    // the promoter window around the TSS
+    pub fn from_gtf(
+        path: &str,
+        header: &HeaderView,
+        gtf_mode: &str,
+    ) -> Result<FeatureSet, Box<dyn Error>> {
+        let mut features: HashMap<u32, Vec<CountFeature>> = HashMap::new();
+        for line in open_text_file(path)?.lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let toks: Vec<&str> = line.split('\t').collect();
+            if toks.len() < 9 || toks[2] != "gene" {
+                continue;
+            }
+
+            let chr = match header.tid(toks[0].as_bytes()) {
+                Some(chr) => chr,
+                None => continue,
+            };
+
+            // GTF is 1-based and inclusive
+            let gene_start: u32 = toks[3].parse::<u32>()? - 1;
+            let gene_end: u32 = toks[4].parse()?;
+            let is_reverse = toks[6] == "-";
+            let chr_len = header.target_len(chr).unwrap() as u32;
+
+            let range = match (gtf_mode, is_reverse) {
+                ("genebody", false) => Range {
+                    start: gene_start.saturating_sub(GENE_UPSTREAM),
+                    end: gene_end,
+                },
+                ("genebody", true) => Range {
+                    start: gene_start,
+                    end: std::cmp::min(chr_len, gene_end + GENE_UPSTREAM),
+                },
+                ("promoter", false) => Range {
+                    start: gene_start.saturating_sub(PROMOTER_UPSTREAM),
+                    end: std::cmp::min(chr_len, gene_start + PROMOTER_DOWNSTREAM),
+                },
+                ("promoter", true) => Range {
+                    start: gene_end.saturating_sub(PROMOTER_DOWNSTREAM),
+                    end: std::cmp::min(chr_len, gene_end + PROMOTER_UPSTREAM),
+                },
+                _ => unreachable!(),
+            };
+
+            let name = gtf_attribute(toks[8], "gene_name")
+                .or_else(|| gtf_attribute(toks[8], "gene_id"))
+                .expect("can't find gene name in the GTF record");
+            features
+                .entry(chr)
+                .or_insert_with(Vec::new)
+                .push(CountFeature {
+                    chr_name: toks[0].to_string(),
+                    range,
+                    name,
+                });
+        }
+
+        Ok(FeatureSet::new(features))
+    }
+
+    // genome wide fixed size tiles
+    pub fn from_bins(header: &HeaderView, bin_size: u32) -> FeatureSet {
+        assert!(bin_size > 0, "bin size has to be positive");
+
+        let mut features: HashMap<u32, Vec<CountFeature>> = HashMap::new();
+        for chr in 0..header.target_count() {
+            let chr_len = header.target_len(chr).unwrap() as u32;
+            let chr_name = chr_name(Some(header), chr);
+
+            let chr_features = features.entry(chr).or_insert_with(Vec::new);
+            for start in (0..chr_len).step_by(bin_size as usize) {
+                let range = Range {
+                    start,
+                    end: std::cmp::min(chr_len, start + bin_size),
+                };
+                let name = range_name(&chr_name, &range);
+                chr_features.push(CountFeature {
+                    chr_name: chr_name.clone(),
+                    range,
+                    name,
+                });
+            }
+        }
+
+        FeatureSet::new(features)
+    }
+
+    fn sorted_chrs(&self) -> Vec<u32> {
+        let mut chrs: Vec<u32> = self.features.keys().cloned().collect();
+        chrs.sort_unstable();
+        chrs
+    }
+
+    // the matrix rows are ordered by the chr id and the feature start, this
+    // is the row of the first feature of every chr
+    pub fn row_offsets(&self) -> HashMap<u32, usize> {
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+        for chr in self.sorted_chrs() {
+            offsets.insert(chr, offset);
+            offset += self.features[&chr].len();
+        }
+
+        offsets
+    }
+
+    pub fn into_rows(mut self) -> Vec<CountFeature> {
+        let mut rows = Vec::with_capacity(self.num_features());
+        for chr in self.sorted_chrs() {
+            rows.extend(self.features.remove(&chr).unwrap());
+        }
+
+        rows
+    }
+
+    pub fn chr_features(&self, chr: u32) -> Option<&Vec<CountFeature>> {
+        self.features.get(&chr)
+    }
+
+    pub fn num_features(&self) -> usize {
+        self.features.values().map(|x| x.len()).sum()
+    }
+}
+
+fn gtf_attribute(attributes: &str, key: &str) -> Option<String> {
+    for attribute in attributes.split(';') {
+        let mut toks = attribute.trim().splitn(2, ' ');
+        if toks.next() == Some(key) {
+            return toks.next().map(|val| val.trim_matches('"').to_string());
+        }
+    }
+
+    None
+}
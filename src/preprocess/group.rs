@@ -6,11 +6,39 @@ use std::io::{BufReader, BufWriter};
 use std::ops::Range;
 use std::path::Path;
 
-use crate::quantify::fragments::{Fragment, FragmentFile};
+use crate::fragments::schema::{Fragment, FragmentFile};
 use clap::ArgMatches;
 use itertools::Itertools;
 use num_format::{Locale, ToFormattedString};
 
+// Joint classes of the fragments of a chromosome, i.e. the sorted and
+// deduplicated ids sharing the same fragment coordinates. The id is given
+// by the key, usually the barcode, and the fragments w/o one are skipped.
+pub fn joint_classes<I, F>(frags: I, mut key: F) -> HashMap<Range<u64>, Vec<u64>>
+where
+    I: Iterator<Item = Fragment>,
+    F: FnMut(&Fragment) -> Option<u64>,
+{
+    let mut joint_class = HashMap::with_capacity(500);
+    for frag in frags {
+        if let Some(id) = key(&frag) {
+            joint_class
+                .entry(Range {
+                    start: frag.start,
+                    end: frag.end,
+                })
+                .or_insert_with(Vec::new)
+                .push(id);
+        }
+    }
+
+    for ids in joint_class.values_mut() {
+        ids.sort_unstable();
+        ids.dedup();
+    }
+    joint_class
+}
+
 pub fn dedup(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let report_all_cb = match sub_m.occurrences_of("allcb") {
         0 => false,
@@ -39,29 +67,19 @@ pub fn dedup(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let mut total_group = 0;
     let mut total_classes = 0;
 
-    let mut joint_class = HashMap::with_capacity(500);
     for (chr, chr_group) in FragmentFile::new(input_bed)
-        .map(|maybe_frag| maybe_frag)
         .group_by(|frag| frag.chr)
         .into_iter()
     {
         print!("\rWorking on Chromosome: {}", chr);
         std::io::stdout().flush().expect("Can't flush output");
 
-        for frag in chr_group {
-            let val = joint_class
-                .entry(Range {
-                    start: frag.start,
-                    end: frag.end,
-                })
-                .or_insert_with(Vec::new);
-            val.push(frag.cb);
-        }
+        let chr_classes = joint_classes(chr_group, |frag| {
+            total_frag += 1;
+            Some(frag.cb)
+        });
 
-        for (range, mut cbs) in joint_class.drain() {
-            total_frag += cbs.len();
-            cbs.sort_unstable();
-            cbs.dedup();
+        for (range, cbs) in chr_classes {
             total_classes += 1;
             total_group += cbs.len();
 
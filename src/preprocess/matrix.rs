@@ -0,0 +1,346 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+#[cfg(feature = "hdf5")]
+use hdf5::types::VarLenUnicode;
+#[cfg(feature = "hdf5")]
+use hdf5::{Group, H5Type, Location};
+use serde::{Deserialize, Serialize};
+
+use crate::preprocess::feature_set::CountFeature;
+
+// (row, col, count) entry of the final matrix
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatrixEntry {
+    pub row: u32,
+    pub col: u32,
+    pub count: u32,
+}
+
+impl MatrixEntry {
+    pub fn write(&self, file: &mut BufWriter<File>) -> Result<(), Box<dyn Error>> {
+        let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
+        file.write_all(&encoded)?;
+        Ok(())
+    }
+}
+
+pub struct EntryFile {
+    buf: BufReader<File>,
+    mem_block: [u8; 12],
+}
+
+impl EntryFile {
+    pub fn new(buf: BufReader<File>) -> EntryFile {
+        EntryFile {
+            buf,
+            mem_block: [0; 12],
+        }
+    }
+}
+
+impl Iterator for EntryFile {
+    type Item = MatrixEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.read_exact(&mut self.mem_block).ok()?;
+        bincode::deserialize(&self.mem_block[..]).ok()
+    }
+}
+
+// Feature x barcode count matrix along with the row and column names, obs
+// holds the per barcode QC columns. The entries stay on disk in row order
+// and are streamed into the output, only the per barcode number of entries
+// is kept in memory for the column major layouts.
+pub struct CountMatrix {
+    pub entries_path: PathBuf,
+    pub shape: (usize, usize),
+    pub col_nnz: Vec<usize>,
+    pub features: Vec<CountFeature>,
+    pub barcodes: Vec<String>,
+    pub obs: Vec<(String, Vec<f32>)>,
+}
+
+fn gz_writer(file_path: &Path) -> Result<GzEncoder<BufWriter<File>>, Box<dyn Error>> {
+    info!("Creating output file: {:?}", file_path);
+    let file = BufWriter::new(File::create(file_path)?);
+    Ok(GzEncoder::new(file, Compression::default()))
+}
+
+#[cfg(feature = "hdf5")]
+fn write_h5_strings(group: &Group, name: &str, values: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut h5_values = Vec::with_capacity(values.len());
+    for value in values {
+        h5_values.push(value.parse::<VarLenUnicode>()?);
+    }
+
+    group
+        .new_dataset::<VarLenUnicode>()
+        .create(name, h5_values.len())?
+        .write(&h5_values)?;
+    Ok(())
+}
+
+#[cfg(feature = "hdf5")]
+fn write_h5_array<T: H5Type>(
+    group: &Group,
+    name: &str,
+    values: &[T],
+) -> Result<(), Box<dyn Error>> {
+    group
+        .new_dataset::<T>()
+        .create(name, values.len())?
+        .write(values)?;
+    Ok(())
+}
+
+#[cfg(feature = "hdf5")]
+fn write_h5_attr(location: &Location, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    location
+        .new_attr::<VarLenUnicode>()
+        .create(name, ())?
+        .write_scalar(&value.parse::<VarLenUnicode>()?)?;
+    Ok(())
+}
+
+// anndata dataframe attributes and index, the columns are written
+// by the caller as separate datasets in the same group
+#[cfg(feature = "hdf5")]
+fn write_h5ad_dataframe(
+    group: &Group,
+    index: &[String],
+    column_names: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    write_h5_attr(group, "encoding-type", "dataframe")?;
+    write_h5_attr(group, "encoding-version", "0.1.0")?;
+    write_h5_attr(group, "_index", "_index")?;
+    write_h5_strings(group, "_index", index)?;
+
+    let mut column_order = Vec::with_capacity(column_names.len());
+    for name in column_names {
+        column_order.push(name.parse::<VarLenUnicode>()?);
+    }
+
+    group
+        .new_attr::<VarLenUnicode>()
+        .create("column-order", column_order.len())?
+        .write(&column_order)?;
+    Ok(())
+}
+
+// the HDF5 layouts are only available w/ the hdf5 feature, checked before
+// any counting is done
+pub fn check_layout(layout: &str) -> Result<(), Box<dyn Error>> {
+    match layout {
+        "h5" | "h5ad" if !cfg!(feature = "hdf5") => Err(format!(
+            "the {} layout needs volans built w/ --features hdf5",
+            layout
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+impl CountMatrix {
+    pub fn write(&self, out_dir: &Path, layout: &str) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(out_dir)?;
+        match layout {
+            "legacy" => self.write_legacy(out_dir),
+            "tenx" => self.write_tenx(out_dir),
+            #[cfg(feature = "hdf5")]
+            "h5" => self.write_h5(&out_dir.join("filtered_peak_bc_matrix.h5")),
+            #[cfg(feature = "hdf5")]
+            "h5ad" => self.write_h5ad(&out_dir.join("counts.h5ad")),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.col_nnz.iter().sum()
+    }
+
+    fn entries(&self) -> Result<EntryFile, Box<dyn Error>> {
+        Ok(EntryFile::new(BufReader::new(File::open(
+            &self.entries_path,
+        )?)))
+    }
+
+    fn write_mtx<W: Write>(&self, file: &mut W) -> Result<(), Box<dyn Error>> {
+        writeln!(file, "%%MatrixMarket matrix coordinate integer general")?;
+        writeln!(file, "%")?;
+        writeln!(file, "{} {} {}", self.shape.0, self.shape.1, self.nnz())?;
+        for entry in self.entries()? {
+            writeln!(file, "{} {} {}", entry.row + 1, entry.col + 1, entry.count)?;
+        }
+
+        Ok(())
+    }
+
+    // barcode major arrays, built w/ a counting sort over the row ordered
+    // entries so the row indices of every column come out sorted
+    #[cfg(feature = "hdf5")]
+    fn csc_arrays(&self) -> Result<(Vec<i64>, Vec<i64>, Vec<u32>), Box<dyn Error>> {
+        let mut indptr: Vec<i64> = vec![0; self.shape.1 + 1];
+        for (col, num_entries) in self.col_nnz.iter().enumerate() {
+            indptr[col + 1] = indptr[col] + *num_entries as i64;
+        }
+
+        let mut offsets: Vec<usize> = indptr.iter().map(|x| *x as usize).collect();
+        let mut indices: Vec<i64> = vec![0; self.nnz()];
+        let mut data: Vec<u32> = vec![0; self.nnz()];
+        for entry in self.entries()? {
+            let offset = &mut offsets[entry.col as usize];
+            indices[*offset] = entry.row as i64;
+            data[*offset] = entry.count;
+            *offset += 1;
+        }
+
+        Ok((indptr, indices, data))
+    }
+
+    fn write_legacy(&self, out_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mtx_file_path = out_dir.join("counts.mtx");
+        info!("Creating output MTX file: {:?}", mtx_file_path);
+        {
+            let mut file = BufWriter::new(File::create(mtx_file_path)?);
+            self.write_mtx(&mut file)?;
+            file.flush()?;
+        }
+
+        let mut file = BufWriter::new(File::create(out_dir.join("counts_rows.txt"))?);
+        for feature in &self.features {
+            writeln!(file, "{}", feature.name)?;
+        }
+
+        let mut file = BufWriter::new(File::create(out_dir.join("counts_cols.txt"))?);
+        for barcode in &self.barcodes {
+            writeln!(file, "{}", barcode)?;
+        }
+
+        Ok(())
+    }
+
+    // Cell Ranger layout, matrix.mtx.gz w/ features.tsv.gz and barcodes.tsv.gz
+    fn write_tenx(&self, out_dir: &Path) -> Result<(), Box<dyn Error>> {
+        {
+            let mut file = gz_writer(&out_dir.join("matrix.mtx.gz"))?;
+            self.write_mtx(&mut file)?;
+            file.finish()?;
+        }
+
+        {
+            let mut file = gz_writer(&out_dir.join("features.tsv.gz"))?;
+            for feature in &self.features {
+                writeln!(
+                    file,
+                    "{}\t{}\tPeaks\t{}\t{}\t{}",
+                    feature.interval(),
+                    feature.name,
+                    feature.chr_name,
+                    feature.range.start,
+                    feature.range.end
+                )?;
+            }
+            file.finish()?;
+        }
+
+        {
+            let mut file = gz_writer(&out_dir.join("barcodes.tsv.gz"))?;
+            for barcode in &self.barcodes {
+                writeln!(file, "{}", barcode)?;
+            }
+            file.finish()?;
+        }
+
+        Ok(())
+    }
+
+    // filtered_peak_bc_matrix.h5 schema, stored as CSC w/ barcodes as columns
+    #[cfg(feature = "hdf5")]
+    fn write_h5(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
+        info!("Creating output HDF5 file: {:?}", file_path);
+        let (indptr, indices, data) = self.csc_arrays()?;
+
+        let file = hdf5::File::create(file_path)?;
+        let group = file.create_group("matrix")?;
+        write_h5_strings(&group, "barcodes", &self.barcodes)?;
+
+        let data: Vec<i32> = data.into_iter().map(|x| x as i32).collect();
+        write_h5_array(&group, "data", &data)?;
+        write_h5_array(&group, "indices", &indices)?;
+        write_h5_array(&group, "indptr", &indptr)?;
+        let shape = vec![self.shape.0 as i32, self.shape.1 as i32];
+        write_h5_array(&group, "shape", &shape)?;
+
+        let features = group.create_group("features")?;
+        let intervals: Vec<String> = self.features.iter().map(|x| x.interval()).collect();
+        let names: Vec<String> = self.features.iter().map(|x| x.name.clone()).collect();
+        let num_features = self.features.len();
+        write_h5_strings(&features, "id", &intervals)?;
+        write_h5_strings(&features, "name", &names)?;
+        write_h5_strings(
+            &features,
+            "feature_type",
+            &vec!["Peaks".to_string(); num_features],
+        )?;
+        write_h5_strings(&features, "genome", &vec![String::new(); num_features])?;
+        write_h5_strings(&features, "interval", &intervals)?;
+        write_h5_strings(
+            &features,
+            "_all_tag_keys",
+            &["genome".to_string(), "interval".to_string()],
+        )?;
+
+        Ok(())
+    }
+
+    // AnnData schema, X is barcode x feature CSR which shares the
+    // layout of the feature x barcode CSC matrix
+    #[cfg(feature = "hdf5")]
+    fn write_h5ad(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
+        info!("Creating output AnnData file: {:?}", file_path);
+        let (indptr, indices, data) = self.csc_arrays()?;
+
+        let file = hdf5::File::create(file_path)?;
+        write_h5_attr(&file, "encoding-type", "anndata")?;
+        write_h5_attr(&file, "encoding-version", "0.1.0")?;
+
+        let group = file.create_group("X")?;
+        write_h5_attr(&group, "encoding-type", "csr_matrix")?;
+        write_h5_attr(&group, "encoding-version", "0.1.0")?;
+        group
+            .new_attr::<i64>()
+            .create("shape", 2)?
+            .write(&[self.shape.1 as i64, self.shape.0 as i64])?;
+
+        let data: Vec<f32> = data.into_iter().map(|x| x as f32).collect();
+        write_h5_array(&group, "data", &data)?;
+        write_h5_array(&group, "indices", &indices)?;
+        write_h5_array(&group, "indptr", &indptr)?;
+
+        let obs = file.create_group("obs")?;
+        for (name, values) in &self.obs {
+            write_h5_array(&obs, name, values)?;
+        }
+        let obs_names: Vec<&str> = self.obs.iter().map(|(name, _)| name.as_str()).collect();
+        write_h5ad_dataframe(&obs, &self.barcodes, &obs_names)?;
+
+        let var = file.create_group("var")?;
+        let intervals: Vec<String> = self.features.iter().map(|x| x.interval()).collect();
+        let names: Vec<String> = self.features.iter().map(|x| x.name.clone()).collect();
+        let chr_names: Vec<String> = self.features.iter().map(|x| x.chr_name.clone()).collect();
+        let starts: Vec<i64> = self.features.iter().map(|x| x.range.start as i64).collect();
+        let ends: Vec<i64> = self.features.iter().map(|x| x.range.end as i64).collect();
+        write_h5_strings(&var, "name", &names)?;
+        write_h5_strings(&var, "chr", &chr_names)?;
+        write_h5_array(&var, "start", &starts)?;
+        write_h5_array(&var, "end", &ends)?;
+        write_h5ad_dataframe(&var, &intervals, &["name", "chr", "start", "end"])?;
+
+        Ok(())
+    }
+}
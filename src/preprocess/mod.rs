@@ -1,10 +1,9 @@
 pub mod barcode;
 pub mod count;
-pub mod filter;
-pub mod fragments;
-pub mod fstats;
+pub mod feature_set;
 pub mod group;
+pub mod matrix;
 pub mod peak;
 pub mod sort;
 pub mod stats;
-pub mod text;
\ No newline at end of file
+pub mod text;
@@ -5,12 +5,87 @@ use std::io::{BufReader, BufWriter};
 use std::ops::Range;
 use std::path::Path;
 
-use crate::quantify::fragments::{Feature, Fragment, FragmentFile};
+use crate::fragments::schema::{Feature, Fragment, FragmentFile};
 use clap::ArgMatches;
 use itertools::Itertools;
 
+use crate::rust_htslib::bam::Read;
+use rust_htslib::bam;
+
 use bitvector::*;
 use num_format::{Locale, ToFormattedString};
+use rayon::prelude::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Peak {
+    pub start: u32,
+    pub end: u32,
+    pub summit: u32,
+    pub score: u32,
+    pub pileup: u16,
+}
+
+impl Peak {
+    // re-centre the peak on its summit with a fixed width, shifted inwards
+    // at the chromosome ends
+    pub fn resize(&mut self, width: u32, chr_len: u32) {
+        let half_width = width / 2;
+        self.end = std::cmp::min(chr_len, self.summit.saturating_sub(half_width) + width);
+        self.start = self.end.saturating_sub(width);
+    }
+
+    // merges an overlapping peak, the summit of the higher pileup is kept
+    fn merge(&mut self, other: &Peak) {
+        if other.pileup > self.pileup {
+            self.summit = other.summit;
+            self.score = other.score;
+            self.pileup = other.pileup;
+        }
+        self.end = std::cmp::max(self.end, other.end);
+    }
+
+    pub fn write(
+        &self,
+        mut file: &mut BufWriter<File>,
+        write_mode: &str,
+        chr: u32,
+        chr_name: &str,
+        peak_name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        // narrowPeak and BED6 scores are capped at 1000 by the spec
+        let bed_score = std::cmp::min(1000, self.score * 10);
+        match write_mode {
+            "narrowpeak" => writeln!(
+                &mut file,
+                "{}\t{}\t{}\t{}\t{}\t.\t{}\t-1\t-1\t{}",
+                chr_name,
+                self.start,
+                self.end,
+                peak_name,
+                bed_score,
+                self.pileup,
+                self.summit - self.start
+            )?,
+            "bed6" => writeln!(
+                &mut file,
+                "{}\t{}\t{}\t{}\t{}\t.",
+                chr_name, self.start, self.end, peak_name, bed_score
+            )?,
+            "binary" => {
+                let frag = Fragment {
+                    chr,
+                    start: self.start as u64,
+                    end: self.end as u64,
+                    cb: self.score as u64,
+                };
+                frag.write(file, "binary")?
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
 
 fn process_pileup(feat_pile_up: &[u16]) -> Option<f32> {
     let feature_len = feat_pile_up.len();
@@ -36,7 +111,7 @@ fn process_pileup(feat_pile_up: &[u16]) -> Option<f32> {
     Some(prob)
 }
 
-fn process_feature_group(features: &[&Feature]) -> Option<Vec<Feature>> {
+fn process_feature_group(features: &[&Feature], window_size: i64) -> Option<Vec<Peak>> {
     let region_start = features.first().unwrap().start as i64;
     let region_end = features.iter().rev().map(|x| x.end).max().unwrap() as i64;
     let region_size: usize = std::cmp::max(0, region_end - region_start + 1) as usize;
@@ -55,7 +130,7 @@ fn process_feature_group(features: &[&Feature]) -> Option<Vec<Feature>> {
     let mut sorted_feature_indices: Vec<usize> = (0..region_size).collect();
     sorted_feature_indices.sort_unstable_by(|a, b| pile_up[*a].cmp(&pile_up[*b]).reverse());
 
-    let mut peaks: Vec<Feature> = Vec::new();
+    let mut peaks: Vec<Peak> = Vec::new();
     for feat_idx in sorted_feature_indices {
         if bitvec.contains(feat_idx) {
             continue;
@@ -65,11 +140,8 @@ fn process_feature_group(features: &[&Feature]) -> Option<Vec<Feature>> {
         }
         bitvec.insert(feat_idx);
 
-        let start = std::cmp::max(0, feat_idx as i64 - crate::configs::WINDOW_SIZE) as usize;
-        let end = std::cmp::min(
-            pile_up.len(),
-            (feat_idx as i64 + crate::configs::WINDOW_SIZE) as usize,
-        );
+        let start = std::cmp::max(0, feat_idx as i64 - window_size) as usize;
+        let end = std::cmp::min(pile_up.len(), (feat_idx as i64 + window_size) as usize);
 
         let peak_prob: Option<f32> = process_pileup(&pile_up[start..end]);
         match peak_prob {
@@ -78,10 +150,12 @@ fn process_feature_group(features: &[&Feature]) -> Option<Vec<Feature>> {
                 for i in start..end + 1 {
                     bitvec.insert(i);
                 }
-                peaks.push(Feature {
+                peaks.push(Peak {
                     start: region_start as u32 + start as u32,
                     end: region_start as u32 + end as u32,
-                    count: ((1.0 - prob) * 100.0) as u32,
+                    summit: region_start as u32 + feat_idx as u32,
+                    score: ((1.0 - prob) * 100.0) as u32,
+                    pileup: pile_up[feat_idx],
                 });
             }
         };
@@ -95,7 +169,85 @@ fn process_feature_group(features: &[&Feature]) -> Option<Vec<Feature>> {
     Some(peaks)
 }
 
+// resized peaks can overlap their neighbours, these are merged to keep the
+// output sorted and non-overlapping
+fn resize_peaks(peaks: Vec<Peak>, width: u32, chr_len: u32) -> Vec<Peak> {
+    let mut resized: Vec<Peak> = peaks
+        .into_iter()
+        .map(|mut peak| {
+            peak.resize(width, chr_len);
+            peak
+        })
+        .collect();
+    resized.sort_unstable_by(|a, b| (a.start, a.end).cmp(&(b.start, b.end)));
+
+    let mut merged: Vec<Peak> = Vec::with_capacity(resized.len());
+    for peak in resized {
+        match merged.last_mut() {
+            Some(last) if peak.start < last.end => last.merge(&peak),
+            _ => merged.push(peak),
+        };
+    }
+
+    merged
+}
+
 pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let bam_header = match sub_m.value_of("bam") {
+        Some(path) => {
+            let bam_file_path = Path::new(path)
+                .canonicalize()
+                .expect("can't find absolute path of input BAM file");
+            info!("Found BAM file: {:?}", bam_file_path);
+
+            let input_bam = bam::Reader::from_path(bam_file_path).expect("Can't open BAM file");
+            Some(input_bam.header().clone())
+        }
+        None => None,
+    };
+
+    // the fixed width peaks are clamped to the chromosome length
+    let chr_len = |chr: u32| -> u32 {
+        let header = bam_header
+            .as_ref()
+            .expect("fixed width peaks need the --bam header");
+        header
+            .target_len(chr)
+            .expect("can't find chromosome length") as u32
+    };
+
+    let out_mode = sub_m.value_of("format").unwrap_or("binary");
+    let out_extension = match out_mode {
+        "narrowpeak" => ".narrowPeak",
+        "bed6" => ".peaks.text.bed",
+        "binary" => ".peaks.bed",
+        _ => unreachable!(),
+    };
+
+    let window_size: i64 = match sub_m.value_of("window") {
+        Some(val) => val.parse().expect("can't parse window size"),
+        None => crate::configs::WINDOW_SIZE,
+    };
+
+    let peak_width: Option<u32> = sub_m
+        .value_of("width")
+        .map(|val| val.parse().expect("can't parse fixed peak width"));
+    if let Some(width) = peak_width {
+        info!(
+            "Re-centering all peaks on summit with fixed width {}",
+            width
+        );
+    }
+
+    let num_threads: usize = match sub_m.value_of("threads") {
+        Some(val) => val.parse().expect("can't parse number of threads"),
+        None => 1,
+    };
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()?;
+    info!("Calling peaks using {} threads", num_threads);
+
     let bed_file_path = Path::new(sub_m.value_of("ibed").expect("can't find BED flag"))
         .canonicalize()
         .expect("can't find absolute path of input bed file");
@@ -109,7 +261,7 @@ pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
         .to_str()
         .unwrap()
         .to_owned()
-        + ".peaks.bed";
+        + out_extension;
     info!("Creating peak BED file: {:?}", peak_file_path);
     let mut output_bed =
         BufWriter::new(File::create(peak_file_path).expect("Can't create BED file"));
@@ -126,6 +278,12 @@ pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     {
         print!("\rWorking on Chromosome: {}", chr);
         std::io::stdout().flush().expect("Can't flush output");
+        let chr_name = match &bam_header {
+            Some(header) => std::str::from_utf8(header.tid2name(chr))
+                .unwrap()
+                .to_string(),
+            None => chr.to_string(),
+        };
         // TODO: Remove
         // if chr != 3 { continue; }
 
@@ -178,6 +336,7 @@ pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
             }
         }
 
+        let mut workable_groups: Vec<&Vec<&Feature>> = Vec::new();
         for features in &features_group {
             let num_supporting_barcodes = features.iter().map(|x| x.count).sum::<u32>();
 
@@ -200,25 +359,26 @@ pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
             // else { continue; }
 
             total_classes += 1;
-            // TODO: Remove
-            // let mut tput = false;
-            // let peaks = match process_feature_group(&workable_feats) {
-            let peaks = match process_feature_group(features) {
-                Some(peaks) => peaks,
-                None => continue,
-            };
+            workable_groups.push(features);
+        }
 
-            total_peaks += peaks.len();
-            for peak in peaks {
-                let frag = Fragment {
-                    chr,
-                    start: peak.start as u64,
-                    end: peak.end as u64,
-                    cb: peak.count as u64,
-                };
+        // region groups are independent, collecting in order keeps the output sorted
+        let group_peaks: Vec<Option<Vec<Peak>>> = thread_pool.install(|| {
+            workable_groups
+                .par_iter()
+                .map(|features| process_feature_group(features, window_size))
+                .collect()
+        });
 
-                frag.write(&mut output_bed, "binary")?;
-            }
+        let mut chr_peaks: Vec<Peak> = group_peaks.into_iter().flatten().flatten().collect();
+        if let Some(width) = peak_width {
+            chr_peaks = resize_peaks(chr_peaks, width, chr_len(chr));
+        }
+
+        for peak in chr_peaks {
+            total_peaks += 1;
+            let peak_name = format!("peak_{}", total_peaks);
+            peak.write(&mut output_bed, out_mode, chr, &chr_name, &peak_name)?;
         }
 
         features.clear();
@@ -251,3 +411,51 @@ pub fn callpeak(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(start: u32, end: u32, summit: u32, pileup: u16) -> Peak {
+        Peak {
+            start,
+            end,
+            summit,
+            score: pileup as u32,
+            pileup,
+        }
+    }
+
+    #[test]
+    fn resize_centres_on_summit() {
+        let mut p = peak(100, 400, 250, 5);
+        p.resize(100, 1_000);
+        assert_eq!((p.start, p.end), (200, 300));
+    }
+
+    #[test]
+    fn resize_clamps_at_chromosome_edges() {
+        let mut p = peak(0, 40, 10, 5);
+        p.resize(100, 1_000);
+        assert_eq!((p.start, p.end), (0, 100));
+
+        let mut p = peak(950, 1_000, 990, 5);
+        p.resize(100, 1_000);
+        assert_eq!((p.start, p.end), (900, 1_000));
+    }
+
+    #[test]
+    fn resize_peaks_merges_overlaps_and_sorts() {
+        let peaks = vec![
+            peak(500, 600, 550, 3),
+            peak(100, 200, 150, 2),
+            peak(120, 260, 180, 7),
+        ];
+        let merged = resize_peaks(peaks, 100, 1_000);
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!((merged[0].start, merged[0].end), (100, 230));
+        assert_eq!((merged[0].summit, merged[0].pileup), (180, 7));
+        assert_eq!((merged[1].start, merged[1].end), (500, 600));
+    }
+}
@@ -4,9 +4,9 @@ use std::io::BufReader;
 use std::io::Write;
 use std::path::Path;
 
+use crate::fragments::schema::FragmentFile;
 use clap::ArgMatches;
 use num_format::{Locale, ToFormattedString};
-use crate::fragments::schema::FragmentFile;
 
 pub fn stats(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let bed_file_path = Path::new(sub_m.value_of("ibed").expect("can't find BED flag"))
@@ -18,7 +18,10 @@ pub fn stats(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let mut num_lines = 0;
     for _ in FragmentFile::new(input_bed).into_iter() {
         if num_lines % crate::configs::TMIL == 0 {
-            print!("\rDone processing {}0M reads", num_lines / crate::configs::TMIL);
+            print!(
+                "\rDone processing {}0M reads",
+                num_lines / crate::configs::TMIL
+            );
             std::io::stdout().flush().expect("Can't flush output");
         }
 
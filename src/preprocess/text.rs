@@ -4,7 +4,7 @@ use std::io::Write;
 use std::io::{BufReader, BufWriter};
 use std::path::Path;
 
-use crate::quantify::fragments::Fragment;
+use crate::fragments::schema::Fragment;
 use clap::ArgMatches;
 
 use crate::rust_htslib::bam::Read;
@@ -53,7 +53,10 @@ pub fn convert(sub_m: &ArgMatches) -> Result<(), Box<dyn Error>> {
     while let Ok(frag) = Fragment::read(&mut input_bed, &mut mem_block) {
         num_lines += 1;
         if num_lines % crate::configs::TMIL == 0 {
-            print!("\rDone processing {}0M reads", num_lines / crate::configs::TMIL);
+            print!(
+                "\rDone processing {}0M reads",
+                num_lines / crate::configs::TMIL
+            );
             std::io::stdout().flush().expect("Can't flush output");
         }
 